@@ -0,0 +1,227 @@
+use std::task::{Context, Poll};
+
+use actix_web::dev::{Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::{http, web, Error, HttpRequest};
+use futures::future::{ok, LocalBoxFuture, Ready};
+use futures::FutureExt;
+
+use crate::admin_routes::authorize_admin;
+use crate::config::Config;
+use crate::http_routes::is_authenticated_streamer;
+use crate::timestamp::Timestamp;
+
+/// Log target of the access log, so it can be filtered or routed apart from the rest, e.g.
+/// `RUST_LOG=audit=info`.
+pub const ACCESS_LOG_TARGET: &str = "audit";
+
+/// Middleware logging every request as one JSON line on `ACCESS_LOG_TARGET`, as an audit
+/// trail of who changed a streamer's queue. Mutations are logged at info level, reads at
+/// debug.
+pub struct AccessLog;
+
+impl<S, B> Transform<S> for AccessLog
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Transform = AccessLogMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ok(AccessLogMiddleware { service })
+    }
+}
+
+pub struct AccessLogMiddleware<S> {
+    service: S,
+}
+
+impl<S, B> Service for AccessLogMiddleware<S>
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, context: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(context)
+    }
+
+    fn call(&mut self, service_request: ServiceRequest) -> Self::Future {
+        let timestamp = Timestamp::now();
+
+        self.service
+            .call(service_request)
+            .map(move |service_response| {
+                if let Ok(service_response) = &service_response {
+                    log_access(
+                        timestamp,
+                        service_response.request(),
+                        service_response.status(),
+                    );
+                }
+
+                service_response
+            })
+            .boxed_local()
+    }
+}
+
+fn log_access(timestamp: Timestamp, request: &HttpRequest, status: http::StatusCode) {
+    let method = request.method();
+    let is_mutation = !matches!(
+        *method,
+        http::Method::GET | http::Method::HEAD | http::Method::OPTIONS
+    );
+    let level = if is_mutation {
+        log::Level::Info
+    } else {
+        log::Level::Debug
+    };
+
+    if !log::log_enabled!(target: ACCESS_LOG_TARGET, level) {
+        return;
+    }
+
+    let user_id = request.match_info().get("user_id");
+    let body_size = request
+        .headers()
+        .get(http::header::CONTENT_LENGTH)
+        .and_then(|content_length| content_length.to_str().ok())
+        .and_then(|content_length| content_length.parse::<u64>().ok());
+
+    log::log!(
+        target: ACCESS_LOG_TARGET,
+        level,
+        "{}",
+        serde_json::json!({
+            "timestamp": timestamp,
+            "method": method.as_str(),
+            "path": request.path(),
+            "userId": user_id,
+            "sourceIp": request.connection_info().realip_remote_addr(),
+            "authSubject": auth_subject(request, user_id),
+            "bodySize": body_size,
+            "status": status.as_u16(),
+        })
+    );
+}
+
+/// Who the request authenticated as: `admin`, `streamer:<user_id>`, or nobody.
+fn auth_subject(request: &HttpRequest, user_id: Option<&str>) -> Option<String> {
+    let config = request.app_data::<web::Data<Config>>()?;
+
+    if authorize_admin(request, config).is_ok() {
+        return Some("admin".to_owned());
+    }
+
+    user_id
+        .filter(|user_id| is_authenticated_streamer(request, config, user_id))
+        .map(|user_id| format!("streamer:{}", user_id))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use actix_web::{test, App};
+    use serde_json::{json, Value};
+
+    use super::*;
+    use crate::test_support::TestContext;
+
+    /// Keeps every access log line, since the logger is global to the test binary.
+    struct CapturingLogger {
+        records: Mutex<Vec<(log::Level, String)>>,
+    }
+
+    impl log::Log for CapturingLogger {
+        fn enabled(&self, metadata: &log::Metadata<'_>) -> bool {
+            metadata.target() == ACCESS_LOG_TARGET
+        }
+
+        fn log(&self, record: &log::Record<'_>) {
+            if self.enabled(record.metadata()) {
+                self.records
+                    .lock()
+                    .unwrap()
+                    .push((record.level(), record.args().to_string()));
+            }
+        }
+
+        fn flush(&self) {}
+    }
+
+    static LOGGER: CapturingLogger = CapturingLogger {
+        records: Mutex::new(Vec::new()),
+    };
+
+    #[actix_rt::test]
+    async fn mutations_are_logged_as_json_lines() {
+        let _ = log::set_logger(&LOGGER);
+        log::set_max_level(log::LevelFilter::Debug);
+        let mut config = Config::from_env();
+        config
+            .streamer_tokens_by_user_id
+            .insert("audited".to_owned(), "secret".to_owned());
+        let context = TestContext::new(config);
+        let mut app = test::init_service(
+            App::new()
+                .wrap(AccessLog)
+                .configure(|service_config| context.configure(service_config)),
+        )
+        .await;
+
+        let body = json!({ "songRequestsEnabled": true, "songArrangements": [] });
+        test::call_service(
+            &mut app,
+            test::TestRequest::put()
+                .uri("/audited/songs")
+                .header("Authorization", "Bearer secret")
+                .set_json(&body)
+                // Clients send it, unlike `TestRequest`.
+                .header(http::header::CONTENT_LENGTH, body.to_string().len())
+                .to_request(),
+        )
+        .await;
+        test::call_service(
+            &mut app,
+            test::TestRequest::get()
+                .uri("/audited/songs/requests")
+                .to_request(),
+        )
+        .await;
+
+        let records: Vec<(log::Level, Value)> = LOGGER
+            .records
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(level, line)| (*level, serde_json::from_str(line).unwrap()))
+            .filter(|(_, entry): &(log::Level, Value)| entry["userId"] == "audited")
+            .collect();
+        assert_eq!(records.len(), 2);
+
+        let (level, entry) = &records[0];
+        assert_eq!(*level, log::Level::Info);
+        assert_eq!(entry["method"], "PUT");
+        assert_eq!(entry["path"], "/audited/songs");
+        assert_eq!(entry["authSubject"], "streamer:audited");
+        assert_eq!(entry["bodySize"], body.to_string().len());
+        assert_eq!(entry["status"], 200);
+        assert!(entry["timestamp"].is_string());
+        assert!(entry.get("sourceIp").is_some());
+
+        let (level, entry) = &records[1];
+        assert_eq!(*level, log::Level::Debug);
+        assert_eq!(entry["method"], "GET");
+        assert_eq!(entry["authSubject"], Value::Null);
+    }
+}
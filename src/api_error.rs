@@ -0,0 +1,188 @@
+use std::fmt;
+use std::time::Duration;
+
+use actix_web::http::{header, StatusCode};
+use actix_web::{HttpResponse, ResponseError};
+use serde::Serialize;
+
+/// Errors returned by the HTTP routes, rendered as a JSON body with a matching status code.
+#[derive(Debug)]
+pub enum ApiError {
+    /// The viewer used up their request quota and may retry once the window frees up.
+    QuotaExceeded { retry_after: Duration },
+    /// The action is on cooldown and may be retried once it's over.
+    CoolingDown { retry_after: Duration },
+    /// The requested song was played or removed recently and may be requested again after
+    /// `retry_after`.
+    SongCoolingDown { retry_after: Duration },
+    /// A connection cap was reached, and the client should back off for `retry_after`.
+    TooManyConnections { retry_after: Duration },
+    /// The request was malformed.
+    BadRequest(String),
+    /// The server is shedding load and the client should retry later.
+    Overloaded { retry_after: Duration },
+    /// The request was well-formed but failed validation, with every problem found.
+    Validation(Vec<ValidationError>),
+    /// Song requests are blocked server-wide by panic mode.
+    RequestsSuspended,
+    /// The server already tracks as many users as it's allowed to.
+    TooManyUsers,
+    /// The request is missing valid credentials.
+    Unauthorized,
+    /// The request isn't allowed right now, with a message explaining why.
+    Forbidden(String),
+    /// The request conflicts with the current state.
+    Conflict(String),
+    /// The requested resource doesn't exist.
+    NotFound,
+    /// The resource exists but doesn't support the request's method.
+    MethodNotAllowed { allow: &'static str },
+    /// Something went wrong on the server's side.
+    Internal(String),
+}
+
+/// One problem with a request body. Validation reports all of them at once, so clients
+/// don't have to fix them one retry at a time.
+#[derive(Debug, Serialize)]
+pub struct ValidationError {
+    /// Offending body field, as it's spelled in JSON.
+    field: String,
+    /// Machine-readable kind of problem, e.g. `required`.
+    code: &'static str,
+    message: String,
+}
+
+impl ValidationError {
+    pub fn new(
+        field: impl Into<String>,
+        code: &'static str,
+        message: impl Into<String>,
+    ) -> ValidationError {
+        ValidationError {
+            field: field.into(),
+            code,
+            message: message.into(),
+        }
+    }
+}
+
+impl From<ValidationError> for ApiError {
+    fn from(validation_error: ValidationError) -> ApiError {
+        ApiError::Validation(vec![validation_error])
+    }
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct ApiErrorResponse<'a> {
+    error: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    retry_after_secs: Option<u64>,
+    #[serde(skip_serializing_if = "<[_]>::is_empty")]
+    errors: &'a [ValidationError],
+}
+
+impl ApiError {
+    fn retry_after(&self) -> Option<Duration> {
+        match self {
+            ApiError::QuotaExceeded { retry_after }
+            | ApiError::CoolingDown { retry_after }
+            | ApiError::SongCoolingDown { retry_after }
+            | ApiError::TooManyConnections { retry_after }
+            | ApiError::Overloaded { retry_after } => Some(*retry_after),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for ApiError {
+    fn fmt(&self, formatter: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ApiError::QuotaExceeded { .. } => write!(formatter, "Song request quota exceeded."),
+            ApiError::CoolingDown { .. } => {
+                write!(formatter, "Please wait before doing that again.")
+            }
+            ApiError::SongCoolingDown { .. } => {
+                write!(
+                    formatter,
+                    "This song was played recently, please request it later."
+                )
+            }
+            ApiError::TooManyConnections { .. } => {
+                write!(formatter, "Too many connections, please try again later.")
+            }
+            ApiError::BadRequest(message) => write!(formatter, "{}", message),
+            ApiError::Overloaded { .. } => write!(
+                formatter,
+                "The server is overloaded, please try again later."
+            ),
+            ApiError::Validation(validation_errors) => {
+                let messages: Vec<&str> = validation_errors
+                    .iter()
+                    .map(|validation_error| validation_error.message.as_str())
+                    .collect();
+                write!(formatter, "{}", messages.join(" "))
+            }
+            ApiError::RequestsSuspended => {
+                write!(formatter, "Song requests are temporarily disabled.")
+            }
+            ApiError::TooManyUsers => {
+                write!(formatter, "The server isn't accepting new users.")
+            }
+            ApiError::Unauthorized => write!(formatter, "Unauthorized."),
+            ApiError::Forbidden(message) => write!(formatter, "{}", message),
+            ApiError::Conflict(message) => write!(formatter, "{}", message),
+            ApiError::NotFound => write!(formatter, "Not found."),
+            ApiError::MethodNotAllowed { .. } => write!(formatter, "Method not allowed."),
+            ApiError::Internal(message) => write!(formatter, "{}", message),
+        }
+    }
+}
+
+impl ResponseError for ApiError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            ApiError::QuotaExceeded { .. } => StatusCode::TOO_MANY_REQUESTS,
+            ApiError::CoolingDown { .. } => StatusCode::TOO_MANY_REQUESTS,
+            ApiError::SongCoolingDown { .. } => StatusCode::TOO_MANY_REQUESTS,
+            ApiError::TooManyConnections { .. } => StatusCode::TOO_MANY_REQUESTS,
+            ApiError::BadRequest(_) => StatusCode::BAD_REQUEST,
+            ApiError::Overloaded { .. } => StatusCode::SERVICE_UNAVAILABLE,
+            ApiError::Validation(_) => StatusCode::UNPROCESSABLE_ENTITY,
+            ApiError::RequestsSuspended => StatusCode::SERVICE_UNAVAILABLE,
+            ApiError::TooManyUsers => StatusCode::SERVICE_UNAVAILABLE,
+            ApiError::Unauthorized => StatusCode::UNAUTHORIZED,
+            ApiError::Forbidden(_) => StatusCode::FORBIDDEN,
+            ApiError::Conflict(_) => StatusCode::CONFLICT,
+            ApiError::NotFound => StatusCode::NOT_FOUND,
+            ApiError::MethodNotAllowed { .. } => StatusCode::METHOD_NOT_ALLOWED,
+            ApiError::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        let retry_after_secs = self
+            .retry_after()
+            .map(|retry_after| retry_after.as_secs_f64().ceil() as u64);
+        let mut response = HttpResponse::build(self.status_code());
+
+        if let Some(retry_after_secs) = retry_after_secs {
+            response.header(header::RETRY_AFTER, retry_after_secs.to_string());
+        }
+
+        if let ApiError::MethodNotAllowed { allow } = self {
+            response.header(header::ALLOW, *allow);
+        }
+
+        let errors = match self {
+            ApiError::Validation(validation_errors) => &validation_errors[..],
+            _ => &[],
+        };
+
+        response.json(ApiErrorResponse {
+            error: self.to_string(),
+            retry_after_secs,
+            errors,
+        })
+    }
+}
@@ -0,0 +1,220 @@
+use std::collections::HashMap;
+use std::env;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::Duration;
+
+use time::UtcOffset;
+
+use crate::timestamp::parse_utc_offset;
+
+/// Server configuration, resolved from environment variables at startup.
+#[derive(Clone)]
+pub struct Config {
+    /// Default log filter, e.g. `info` or `debug`. `RUST_LOG` directives still take precedence.
+    pub log_level: Option<String>,
+    /// Emit logs as JSON lines instead of env_logger's default text format.
+    pub json_logs: bool,
+    /// Bearer token required by the `/admin` routes, which are disabled when it's unset.
+    pub admin_token: Option<String>,
+    /// Port the HTTP server binds to.
+    pub port: u16,
+    /// Largest JSON request body accepted, in bytes. Larger bodies are rejected with 413.
+    pub max_json_bytes: usize,
+    /// How many requests a viewer may submit within `request_quota_window`.
+    /// No quota is enforced when unset.
+    pub request_quota_count: Option<usize>,
+    /// Rolling window the request quota is counted over.
+    pub request_quota_window: Duration,
+    /// Total queued requests, across all users, at which new requests start being shed.
+    /// Load shedding is disabled when unset.
+    pub overload_high_water_mark: Option<usize>,
+    /// Total queued requests the server has to drop back to before it accepts requests again.
+    pub overload_low_water_mark: usize,
+    /// How long shed clients are told to wait before retrying.
+    pub overload_retry_after: Duration,
+    /// Secret each streamer authenticates their websocket sessions and playlist changes with,
+    /// from `STREAMER_TOKENS=user_id:token,...`.
+    pub streamer_tokens_by_user_id: HashMap<String, String>,
+    /// Offset timestamps are shown at in human-facing output like `/history`, from
+    /// `DISPLAY_UTC_OFFSET=±HH:MM`. Everything else stays in UTC.
+    pub display_utc_offset: UtcOffset,
+    /// File playlists are saved to and restored from. Nothing is persisted when unset.
+    pub state_file: Option<PathBuf>,
+    /// Most often the state file is written while requests keep changing it.
+    pub state_flush_interval: Duration,
+    /// How long a disconnected websocket session can still be resumed with its resume token.
+    pub resume_grace_period: Duration,
+    /// Extra time a websocket session gets after connecting, on top of the client timeout,
+    /// before it must answer heartbeats, for clients that are slow to start their message loop.
+    pub heartbeat_grace_period: Duration,
+    /// Most websocket sessions a single room accepts. There's no limit when unset.
+    pub max_connections_per_room: Option<usize>,
+    /// Most websocket sessions the server accepts across all rooms. There's no limit when unset.
+    pub max_connections: Option<usize>,
+    /// How often a viewer may bump one of their requests up the queue.
+    pub bump_cooldown: Duration,
+    /// Longest a long poll is held open waiting for a change before it's answered with 304.
+    pub long_poll_timeout: Duration,
+    /// Most users the server keeps playlists for. Playlists for new users are refused
+    /// once it's reached. There's no limit when unset.
+    pub max_users: Option<usize>,
+    /// Where song libraries are read from, from `LIBRARY_SOURCE=file|http`.
+    pub library_source: LibrarySource,
+    /// Shortest time between two app state broadcasts of a room, from `BROADCAST_INTERVAL_MS`.
+    /// Changes in between are sent together once it's over. Every change is sent right away
+    /// when unset.
+    pub broadcast_interval: Option<Duration>,
+    /// Directory of static files, like the viewer and overlay pages, served next to the API.
+    /// Nothing is served when unset.
+    pub static_dir: Option<PathBuf>,
+    /// Path the static files are served under.
+    pub static_mount_path: String,
+    /// File served for directories, from `STATIC_INDEX_FILE`. Set it to an empty value to not
+    /// serve directories.
+    pub static_index_file: Option<String>,
+    /// File in `static_dir` served with a 404 for paths matching nothing. Unmatched paths get the
+    /// default 404 when unset.
+    pub static_not_found_file: Option<String>,
+    /// Word list playlists with `filter_profanity` on mask in what viewers see, one word per
+    /// line. Nothing is masked when unset.
+    pub profanity_word_list_file: Option<PathBuf>,
+    /// URL `{ "event": "connect"|"disconnect", "room", "sessionCount" }` is posted to whenever a
+    /// websocket session joins or leaves a room. Nothing is posted when unset.
+    pub connection_webhook_url: Option<String>,
+    /// Most song requests the server accepts per second, across all users. Requests over it
+    /// are rejected with 503. There's no limit when unset.
+    pub create_rate_limit_per_sec: Option<u32>,
+    /// Most text messages a single websocket session may send per second. Sessions over it are
+    /// closed with code 4029. There's no limit when unset.
+    pub websocket_message_rate_limit_per_sec: Option<u32>,
+    /// Whether song requests start out enabled on playlists created before their streamer set
+    /// them up.
+    pub default_requests_enabled: bool,
+    /// URL viewer tokens are validated against, to check song requests are made by the viewer
+    /// they claim to be. Viewer ids are trusted as sent when unset.
+    pub viewer_auth_url: Option<String>,
+}
+
+/// Where song libraries are read from.
+#[derive(Clone)]
+pub enum LibrarySource {
+    /// `{user_id}.json` files in the working directory.
+    File,
+    /// `{base_url}/{user_id}.json`, with the base URL from `LIBRARY_BASE_URL`.
+    Http { base_url: String },
+}
+
+impl Config {
+    pub fn from_env() -> Config {
+        let overload_high_water_mark: Option<usize> = optional_env_var("OVERLOAD_HIGH_WATER_MARK");
+
+        Config {
+            log_level: env::var("LOG_LEVEL").ok(),
+            json_logs: matches!(env::var("LOG_FORMAT"), Ok(log_format) if log_format.eq_ignore_ascii_case("json")),
+            admin_token: env::var("ADMIN_TOKEN").ok(),
+            port: env_var_or("PORT", 8080),
+            max_json_bytes: env_var_or("MAX_JSON_BYTES", 256 * 1024),
+            request_quota_count: optional_env_var("REQUEST_QUOTA_COUNT"),
+            request_quota_window: Duration::from_secs(env_var_or(
+                "REQUEST_QUOTA_WINDOW_SECS",
+                3600,
+            )),
+            overload_high_water_mark,
+            overload_low_water_mark: env_var_or(
+                "OVERLOAD_LOW_WATER_MARK",
+                overload_high_water_mark.map_or(0, |high_water_mark| high_water_mark * 9 / 10),
+            ),
+            overload_retry_after: Duration::from_secs(env_var_or("OVERLOAD_RETRY_AFTER_SECS", 30)),
+            streamer_tokens_by_user_id: streamer_tokens_env_var("STREAMER_TOKENS"),
+            display_utc_offset: utc_offset_env_var("DISPLAY_UTC_OFFSET"),
+            state_file: optional_env_var("STATE_FILE"),
+            state_flush_interval: Duration::from_secs(env_var_or("STATE_FLUSH_INTERVAL_SECS", 2)),
+            resume_grace_period: Duration::from_secs(env_var_or("RESUME_GRACE_PERIOD_SECS", 30)),
+            heartbeat_grace_period: Duration::from_secs(env_var_or(
+                "HEARTBEAT_GRACE_PERIOD_SECS",
+                0,
+            )),
+            max_connections_per_room: optional_env_var("MAX_CONNECTIONS_PER_ROOM"),
+            max_connections: optional_env_var("MAX_CONNECTIONS"),
+            bump_cooldown: Duration::from_secs(env_var_or("BUMP_COOLDOWN_SECS", 300)),
+            long_poll_timeout: Duration::from_secs(env_var_or("LONG_POLL_TIMEOUT_SECS", 25)),
+            max_users: optional_env_var("MAX_USERS"),
+            library_source: library_source_env_var("LIBRARY_SOURCE", "LIBRARY_BASE_URL"),
+            broadcast_interval: optional_env_var("BROADCAST_INTERVAL_MS")
+                .map(Duration::from_millis),
+            static_dir: optional_env_var("STATIC_DIR"),
+            static_mount_path: env_var_or("STATIC_MOUNT_PATH", "/".to_owned()),
+            static_index_file: Some(env_var_or("STATIC_INDEX_FILE", "index.html".to_owned()))
+                .filter(|static_index_file| !static_index_file.is_empty()),
+            static_not_found_file: optional_env_var("STATIC_NOT_FOUND_FILE"),
+            profanity_word_list_file: optional_env_var("PROFANITY_WORD_LIST_FILE"),
+            connection_webhook_url: optional_env_var("CONNECTION_WEBHOOK_URL"),
+            create_rate_limit_per_sec: optional_env_var("CREATE_RATE_LIMIT_PER_SEC")
+                .filter(|create_rate_limit_per_sec| *create_rate_limit_per_sec > 0),
+            websocket_message_rate_limit_per_sec: optional_env_var(
+                "WEBSOCKET_MESSAGE_RATE_LIMIT_PER_SEC",
+            )
+            .filter(|websocket_message_rate_limit_per_sec| {
+                *websocket_message_rate_limit_per_sec > 0
+            }),
+            default_requests_enabled: env_var_or("DEFAULT_REQUESTS_ENABLED", false),
+            viewer_auth_url: optional_env_var("VIEWER_AUTH_URL"),
+        }
+    }
+}
+
+/// Parse an environment variable, falling back to `default` when it isn't set.
+fn env_var_or<T: FromStr>(name: &str, default: T) -> T {
+    optional_env_var(name).unwrap_or(default)
+}
+
+/// Parse an environment variable, returning `None` when it isn't set.
+fn optional_env_var<T: FromStr>(name: &str) -> Option<T> {
+    env::var(name).ok().map(|value| {
+        value
+            .parse()
+            .unwrap_or_else(|_| panic!("{} has an invalid value: {:?}", name, value))
+    })
+}
+
+/// Parse a comma-separated list of `user_id:token` pairs, returning no tokens when it isn't set.
+fn streamer_tokens_env_var(name: &str) -> HashMap<String, String> {
+    let value = env::var(name).unwrap_or_default();
+
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| match pair.split_once(':') {
+            Some((user_id, token)) if !user_id.is_empty() && !token.is_empty() => {
+                (user_id.to_owned(), token.to_owned())
+            }
+            _ => panic!("{} has an invalid value: {:?}", name, value),
+        })
+        .collect()
+}
+
+/// Parse a UTC offset, defaulting to UTC when it isn't set.
+fn utc_offset_env_var(name: &str) -> UtcOffset {
+    env::var(name).map_or(UtcOffset::UTC, |value| {
+        parse_utc_offset(&value)
+            .unwrap_or_else(|| panic!("{} has an invalid value: {:?}", name, value))
+    })
+}
+
+/// Parse the song library source, defaulting to files when it isn't set.
+/// The HTTP source also requires an `http://` or `https://` base URL.
+fn library_source_env_var(name: &str, base_url_name: &str) -> LibrarySource {
+    match env::var(name).as_deref() {
+        Err(_) | Ok("file") => LibrarySource::File,
+        Ok("http") => match env::var(base_url_name) {
+            Ok(base_url) if base_url.starts_with("http://") || base_url.starts_with("https://") => {
+                LibrarySource::Http { base_url }
+            }
+            Ok(base_url) => panic!("{} has an invalid value: {:?}", base_url_name, base_url),
+            Err(_) => panic!("{} must be set when {} is \"http\"", base_url_name, name),
+        },
+        Ok(value) => panic!("{} has an invalid value: {:?}", name, value),
+    }
+}
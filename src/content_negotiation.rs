@@ -0,0 +1,53 @@
+use actix_web::http::header;
+use actix_web::{Error, HttpRequest, HttpResponse, Responder};
+use futures::future::{ready, Ready};
+use serde::Serialize;
+
+use crate::api_error::ApiError;
+use crate::msgpack;
+
+/// Media type of MessagePack bodies.
+pub const MSGPACK_CONTENT_TYPE: &str = "application/msgpack";
+
+/// Check whether the request's `Accept` header lists MessagePack.
+fn accepts_msgpack(request: &HttpRequest) -> bool {
+    request
+        .headers()
+        .get_all(header::ACCEPT)
+        .filter_map(|accept| accept.to_str().ok())
+        .flat_map(|accept| accept.split(','))
+        .map(|media_range| media_range.split(';').next().unwrap_or_default().trim())
+        .any(|media_type| {
+            media_type.eq_ignore_ascii_case(MSGPACK_CONTENT_TYPE)
+                || media_type.eq_ignore_ascii_case("application/x-msgpack")
+        })
+}
+
+/// Respond with `value` as MessagePack when the client accepts it, or as JSON otherwise.
+pub fn negotiated_response<T: Serialize>(request: &HttpRequest, value: &T) -> HttpResponse {
+    let mut response = HttpResponse::Ok();
+    response.header(header::VARY, "Accept");
+
+    if !accepts_msgpack(request) {
+        return response.json(value);
+    }
+
+    match msgpack::to_vec(value) {
+        Ok(body) => response.content_type(MSGPACK_CONTENT_TYPE).body(body),
+        Err(error) => HttpResponse::from_error(
+            ApiError::Internal(format!("Failed to serialize: {}", error)).into(),
+        ),
+    }
+}
+
+/// Drop-in replacement for `web::Json` on read endpoints that also speak MessagePack.
+pub struct Negotiated<T>(pub T);
+
+impl<T: Serialize> Responder for Negotiated<T> {
+    type Error = Error;
+    type Future = Ready<Result<HttpResponse, Error>>;
+
+    fn respond_to(self, request: &HttpRequest) -> Self::Future {
+        ready(Ok(negotiated_response(request, &self.0)))
+    }
+}
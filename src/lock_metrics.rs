@@ -0,0 +1,113 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{LockResult, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+/// Upper bounds of the lock wait histogram buckets, in seconds.
+pub const LOCK_WAIT_BUCKET_BOUNDS: [f64; 6] = [0.000_01, 0.000_1, 0.001, 0.01, 0.1, 1.0];
+
+/// How long callers waited to acquire a lock, bucketed like a Prometheus histogram.
+#[derive(Default)]
+pub struct LockWaitHistogram {
+    /// Waits per bucket, not cumulative. The last one counts waits over the largest bound.
+    bucket_counts: [AtomicU64; LOCK_WAIT_BUCKET_BOUNDS.len() + 1],
+    count: AtomicU64,
+    sum_nanos: AtomicU64,
+}
+
+impl LockWaitHistogram {
+    fn record(&self, wait: Duration) {
+        let wait_secs = wait.as_secs_f64();
+        let bucket = LOCK_WAIT_BUCKET_BOUNDS
+            .iter()
+            .position(|bound| wait_secs <= *bound)
+            .unwrap_or(LOCK_WAIT_BUCKET_BOUNDS.len());
+
+        self.bucket_counts[bucket].fetch_add(1, Ordering::Relaxed);
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.sum_nanos
+            .fetch_add(wait.as_nanos() as u64, Ordering::Relaxed);
+    }
+
+    /// Cumulative wait counts for each of `LOCK_WAIT_BUCKET_BOUNDS`, as Prometheus expects.
+    pub fn cumulative_bucket_counts(&self) -> Vec<(f64, u64)> {
+        let mut cumulative_count = 0;
+
+        LOCK_WAIT_BUCKET_BOUNDS
+            .iter()
+            .zip(&self.bucket_counts)
+            .map(|(bound, bucket_count)| {
+                cumulative_count += bucket_count.load(Ordering::Relaxed);
+                (*bound, cumulative_count)
+            })
+            .collect()
+    }
+
+    pub fn count(&self) -> u64 {
+        self.count.load(Ordering::Relaxed)
+    }
+
+    pub fn sum_secs(&self) -> f64 {
+        Duration::from_nanos(self.sum_nanos.load(Ordering::Relaxed)).as_secs_f64()
+    }
+}
+
+/// `Mutex` that records how long every `lock` waited, to spot contention before it shows up
+/// as latency.
+pub struct InstrumentedMutex<T> {
+    mutex: Mutex<T>,
+    lock_wait: LockWaitHistogram,
+}
+
+impl<T> InstrumentedMutex<T> {
+    pub fn new(value: T) -> InstrumentedMutex<T> {
+        InstrumentedMutex {
+            mutex: Mutex::new(value),
+            lock_wait: LockWaitHistogram::default(),
+        }
+    }
+
+    /// Same as `Mutex::lock`, recording the wait.
+    pub fn lock(&self) -> LockResult<MutexGuard<'_, T>> {
+        let started_at = Instant::now();
+        let guard = self.mutex.lock();
+        self.lock_wait.record(started_at.elapsed());
+        guard
+    }
+
+    pub fn lock_wait(&self) -> &LockWaitHistogram {
+        &self.lock_wait
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc;
+    use std::sync::Arc;
+    use std::thread;
+
+    #[test]
+    fn contended_locks_record_their_wait() {
+        let mutex = Arc::new(InstrumentedMutex::new(()));
+        let (locked_sender, locked_receiver) = mpsc::channel();
+
+        let holder = {
+            let mutex = Arc::clone(&mutex);
+            thread::spawn(move || {
+                let _guard = mutex.lock().unwrap();
+                locked_sender.send(()).unwrap();
+                thread::sleep(Duration::from_millis(50));
+            })
+        };
+        locked_receiver.recv().unwrap();
+        drop(mutex.lock().unwrap());
+        holder.join().unwrap();
+
+        let lock_wait = mutex.lock_wait();
+        assert_eq!(lock_wait.count(), 2);
+        assert!(lock_wait.sum_secs() >= 0.04, "{}", lock_wait.sum_secs());
+        let cumulative_bucket_counts = lock_wait.cumulative_bucket_counts();
+        assert_eq!(cumulative_bucket_counts[3], (0.01, 1));
+        assert_eq!(cumulative_bucket_counts[4], (0.1, 2));
+    }
+}
@@ -2,23 +2,267 @@ use actix::*;
 use actix_cors::Cors;
 use actix_web::*;
 use std::env;
-use std::sync::Mutex;
+use std::io::Write;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
+use crate::http_routes::advance_song_requests_service;
+use crate::http_routes::approve_song_request_service;
+use crate::http_routes::ban_viewer_service;
+use crate::http_routes::bump_song_request_service;
+use crate::http_routes::count_song_requests_service;
+use crate::http_routes::create_snapshot_service;
 use crate::http_routes::create_song_request_service;
+use crate::http_routes::delete_song_request_by_id_service;
 use crate::http_routes::delete_song_request_service;
+use crate::http_routes::delete_viewer_song_requests_service;
+use crate::http_routes::edit_song_request_service;
+use crate::http_routes::events_service;
+use crate::http_routes::export_song_requests_service;
+use crate::http_routes::get_snapshot_service;
+use crate::http_routes::history_csv_service;
+use crate::http_routes::leaderboard_service;
+use crate::http_routes::list_rooms_service;
+use crate::http_routes::list_song_request_viewers_service;
+use crate::http_routes::list_song_requests_by_arrangement_service;
 use crate::http_routes::list_song_requests_service;
+use crate::http_routes::list_upcoming_song_requests_service;
+use crate::http_routes::method_not_allowed_routes;
+use crate::http_routes::pause_song_requests_service;
+use crate::http_routes::peek_song_request_service;
+use crate::http_routes::poll_song_requests_service;
+use crate::http_routes::reject_song_request_service;
+use crate::http_routes::reorder_song_request_relative_service;
+use crate::http_routes::replace_song_requests_service;
+use crate::http_routes::reroll_song_requests_service;
+use crate::http_routes::reset_leaderboard_service;
+use crate::http_routes::reset_playlist_service;
+use crate::http_routes::static_files_routes;
+use crate::http_routes::unban_viewer_service;
+use crate::http_routes::viewer_ban_service;
+use crate::http_routes::viewer_position_service;
 use crate::http_routes::websocket_service;
-use crate::http_routes::{delete_song_requests_service, list_songs, update_playlist};
-use std::collections::HashMap;
+use crate::http_routes::{
+    delete_song_requests_service, list_songs, song_library_info_service,
+    toggle_arrangement_service, update_playlist,
+};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fmt;
+use std::str::FromStr;
 
 use serde::{Deserialize, Serialize};
+use time::UtcOffset;
 
+use crate::access_log::AccessLog;
+use crate::admin_routes::{
+    disable_panic_mode_service, enable_panic_mode_service, get_config_service,
+    list_sessions_service, merge_playlists_service, metrics_service, PanicMode,
+};
+use crate::api_error::ApiError;
+use crate::config::Config;
+use crate::lock_metrics::InstrumentedMutex;
+use crate::persistence::Persistence;
+use crate::profanity_filter::{profanity_filter, ProfanityFilter};
+use crate::song_library::{song_library_source, SongLibrary, SongLibraryCache};
+use crate::throughput_limiter::ThroughputLimiter;
+use crate::timestamp::Timestamp;
+use crate::viewer_identity::viewer_identity_provider;
+
+mod access_log;
+mod admin_routes;
+mod api_error;
+mod config;
+mod content_negotiation;
 mod http_routes;
+mod lock_metrics;
+mod msgpack;
+mod persistence;
+mod profanity_filter;
+mod song_library;
+#[cfg(test)]
+mod test_support;
+mod throughput_limiter;
+mod timestamp;
+mod viewer_identity;
 mod websocket_server_actor;
 mod websocket_session_actor;
 
 pub struct AppState {
     song_requests_by_user_id: HashMap<String, Playlist>,
+    /// When each viewer submitted their recent requests, keyed by (user id, viewer id).
+    request_instants_by_viewer_key: HashMap<(String, String), VecDeque<Instant>>,
+    song_library_cache: SongLibraryCache,
+    /// Whether new requests are currently being shed because too many are queued.
+    overloaded: bool,
+    /// How many times each song was requested since the server started, or since the
+    /// leaderboard was last reset, per user. Deleting requests doesn't lower the counts.
+    song_request_counts_by_user_id: HashMap<String, HashMap<String, usize>>,
+    /// When each viewer last bumped one of their requests, keyed by (user id, viewer id).
+    bump_instants_by_viewer_key: HashMap<(String, String), Instant>,
+    /// Server-wide cap on accepted song requests per second.
+    create_throughput_limiter: ThroughputLimiter,
+    /// Whether song requests start out enabled on playlists created on the fly.
+    default_song_requests_enabled: bool,
+}
+
+/// Longest accepted user id, which doubles as a websocket room name.
+pub const MAX_USER_ID_LENGTH: usize = 64;
+
+/// Whether `user_id` is non-empty, at most `MAX_USER_ID_LENGTH` long, and only made of ASCII
+/// letters, digits, `-` and `_`.
+pub fn is_valid_user_id(user_id: &str) -> bool {
+    !user_id.is_empty()
+        && user_id.len() <= MAX_USER_ID_LENGTH
+        && user_id.chars().all(|character| {
+            character.is_ascii_alphanumeric() || character == '-' || character == '_'
+        })
+}
+
+impl AppState {
+    pub fn new(song_requests_by_user_id: HashMap<String, Playlist>, config: &Config) -> AppState {
+        AppState {
+            song_requests_by_user_id,
+            request_instants_by_viewer_key: HashMap::new(),
+            song_library_cache: SongLibraryCache::default(),
+            overloaded: false,
+            song_request_counts_by_user_id: HashMap::new(),
+            bump_instants_by_viewer_key: HashMap::new(),
+            create_throughput_limiter: ThroughputLimiter::new(config.create_rate_limit_per_sec),
+            default_song_requests_enabled: config.default_requests_enabled,
+        }
+    }
+
+    /// Total number of queued requests across all users.
+    pub fn total_song_requests(&self) -> usize {
+        self.song_requests_by_user_id
+            .values()
+            .map(|playlist| playlist.song_requests.len())
+            .sum()
+    }
+
+    /// Update and return the overload flag. It's raised once the total number of queued
+    /// requests reaches the high-water mark, and only cleared after it drops back to the
+    /// low-water mark, so it doesn't flap while the total hovers around a single threshold.
+    pub fn update_overloaded(&mut self, high_water_mark: usize, low_water_mark: usize) -> bool {
+        let total_song_requests = self.total_song_requests();
+
+        if !self.overloaded && total_song_requests >= high_water_mark {
+            log::warn!(
+                "Shedding song requests, {} requests are queued.",
+                total_song_requests
+            );
+            self.overloaded = true;
+        } else if self.overloaded && total_song_requests <= low_water_mark {
+            log::info!(
+                "No longer shedding song requests, {} requests are queued.",
+                total_song_requests
+            );
+            self.overloaded = false;
+        }
+
+        self.overloaded
+    }
+
+    /// Record a request against the viewer's rolling quota, or fail with the time left until
+    /// the oldest request in the window expires. Instants outside the window are pruned here.
+    pub fn consume_request_quota(
+        &mut self,
+        user_id: &str,
+        viewer_id: &str,
+        quota_count: usize,
+        quota_window: Duration,
+    ) -> Result<(), ApiError> {
+        let now = Instant::now();
+        let request_instants = self
+            .request_instants_by_viewer_key
+            .entry((user_id.to_owned(), viewer_id.to_owned()))
+            .or_default();
+
+        while let Some(oldest_instant) = request_instants.front() {
+            if now.duration_since(*oldest_instant) < quota_window {
+                break;
+            }
+            request_instants.pop_front();
+        }
+
+        if request_instants.len() >= quota_count {
+            let retry_after = request_instants
+                .front()
+                .map_or(quota_window, |oldest_instant| {
+                    quota_window - now.duration_since(*oldest_instant)
+                });
+            return Err(ApiError::QuotaExceeded { retry_after });
+        }
+
+        request_instants.push_back(now);
+        Ok(())
+    }
+
+    /// Record a bump by the viewer, or fail with the time left until their cooldown is over.
+    /// Bumps whose cooldown is over are pruned here.
+    pub fn consume_bump_cooldown(
+        &mut self,
+        user_id: &str,
+        viewer_id: &str,
+        cooldown: Duration,
+    ) -> Result<(), ApiError> {
+        let now = Instant::now();
+        self.bump_instants_by_viewer_key
+            .retain(|_, bump_instant| now.duration_since(*bump_instant) < cooldown);
+
+        let viewer_key = (user_id.to_owned(), viewer_id.to_owned());
+
+        if let Some(bump_instant) = self.bump_instants_by_viewer_key.get(&viewer_key) {
+            return Err(ApiError::CoolingDown {
+                retry_after: cooldown - now.duration_since(*bump_instant),
+            });
+        }
+
+        self.bump_instants_by_viewer_key.insert(viewer_key, now);
+        Ok(())
+    }
+
+    /// Fail when a playlist for `user_id` would have to be created but `max_users` playlists
+    /// already exist. Users that already have a playlist are always let through.
+    pub fn check_user_capacity(
+        &self,
+        user_id: &str,
+        max_users: Option<usize>,
+    ) -> Result<(), ApiError> {
+        match max_users {
+            Some(max_users)
+                if !self.song_requests_by_user_id.contains_key(user_id)
+                    && self.song_requests_by_user_id.len() >= max_users =>
+            {
+                Err(ApiError::TooManyUsers)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// The playlist of `user_id`, created on first use as long as `max_users` allows it.
+    pub fn playlist_mut(
+        &mut self,
+        user_id: &str,
+        max_users: Option<usize>,
+    ) -> Result<&mut Playlist, ApiError> {
+        self.check_user_capacity(user_id, max_users)?;
+
+        let new_playlist = self.new_playlist();
+
+        Ok(self
+            .song_requests_by_user_id
+            .entry(user_id.to_owned())
+            .or_insert(new_playlist))
+    }
+
+    /// Playlist a user starts out with before they set one up.
+    pub fn new_playlist(&self) -> Playlist {
+        Playlist {
+            song_requests_enabled: self.default_song_requests_enabled,
+            ..Playlist::default()
+        }
+    }
 }
 
 #[derive(Deserialize, Serialize, Clone, PartialEq)]
@@ -27,6 +271,368 @@ pub struct Playlist {
     song_requests_enabled: bool,
     song_arrangements: Vec<ArrangementType>,
     song_requests: Vec<SongRequest>,
+    #[serde(default)]
+    current_request: Option<SongRequest>,
+    #[serde(default)]
+    history: Vec<SongRequest>,
+    /// Notice sent to each websocket session right after it connects to or joins the room.
+    #[serde(default)]
+    join_message: Option<String>,
+    /// Reject requests for songs that aren't in the user's song library, when there is one.
+    #[serde(default)]
+    validate_against_library: bool,
+    /// What happens when a song that's already queued is requested by another viewer.
+    #[serde(default)]
+    duplicate_policy: DuplicatePolicy,
+    /// Fields viewers must fill in, on top of the always required ones, for a request to be accepted.
+    #[serde(default)]
+    required_fields: Vec<RequestField>,
+    /// How many requests can be advanced to before song requests close automatically.
+    /// Requests stay open indefinitely when unset.
+    #[serde(default)]
+    auto_close_after: Option<usize>,
+    /// Countdown of `auto_close_after`: how many more requests will be advanced to before song
+    /// requests close. Restarts whenever the streamer sends `auto_close_after`, and is cleared
+    /// once requests close.
+    #[serde(default)]
+    auto_close_remaining: Option<usize>,
+    /// Hold new requests in `pending_approval` until the streamer approves them.
+    #[serde(default)]
+    require_approval: bool,
+    /// Requests waiting for the streamer's approval before joining the queue.
+    #[serde(default)]
+    pending_approval: Vec<SongRequest>,
+    /// Accept requests without a viewer id, giving each one a generated anonymous viewer.
+    #[serde(default)]
+    allow_anonymous: bool,
+    /// Shown to viewers whose requests are rejected because song requests are disabled.
+    /// A generic message is used when unset.
+    #[serde(default)]
+    closed_message: Option<String>,
+    /// Freezes advancing through the queue while the streamer steps away. Viewers can still
+    /// request songs.
+    #[serde(default)]
+    paused: bool,
+    /// Bumped on every broadcasted change, so clients can tell whether their copy is stale.
+    #[serde(default)]
+    version: u64,
+    /// When song requests open on their own. Cleared once it passed.
+    #[serde(default)]
+    open_at: Option<Timestamp>,
+    /// When song requests close on their own. Cleared once it passed.
+    #[serde(default)]
+    close_at: Option<Timestamp>,
+    /// Most characters of usernames and notes shown to viewers and overlays. Longer ones are cut
+    /// off with an ellipsis there, while the streamer still gets them in full.
+    #[serde(default)]
+    display_max_length: Option<usize>,
+    /// Mask words from the `PROFANITY_WORD_LIST_FILE` list in usernames and notes shown to
+    /// viewers and overlays. The streamer still gets them as written.
+    #[serde(default)]
+    filter_profanity: bool,
+    /// Most queued requests of a single viewer shown to viewers and overlays, so nobody crowds
+    /// out everyone else. Their earliest ones are shown. The streamer still sees them all.
+    #[serde(default)]
+    max_visible_per_viewer: Option<usize>,
+    /// Viewers who can't request songs. Left out of the public view.
+    #[serde(default)]
+    banned_viewer_ids: HashSet<String>,
+    /// Tell the room about rejected song requests, and why, e.g. for moderation dashboards.
+    #[serde(default)]
+    broadcast_rejections: bool,
+    /// How long a song can't be requested again after it's played or removed from the queue.
+    /// Songs can be requested again right away when unset.
+    #[serde(default)]
+    song_cooldown_secs: Option<u64>,
+    /// When each song was last played or removed, for the song cooldown. Kept in memory only.
+    #[serde(skip)]
+    finished_instants_by_song_id: HashMap<String, Instant>,
+    /// Named copies of the queue, oldest first. Kept in memory only.
+    #[serde(skip)]
+    snapshots: Vec<SongRequestsSnapshot>,
+}
+
+/// The queue as it was at some moment, e.g. for a highlight clip.
+#[derive(Serialize, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct SongRequestsSnapshot {
+    name: String,
+    taken_at: Timestamp,
+    song_requests: Vec<SongRequest>,
+}
+
+impl Default for Playlist {
+    fn default() -> Playlist {
+        Playlist {
+            song_requests_enabled: false,
+            song_arrangements: vec![
+                ArrangementType::Lead,
+                ArrangementType::Rhythm,
+                ArrangementType::Bass,
+                ArrangementType::Drums,
+                ArrangementType::Vocals,
+            ],
+            song_requests: vec![],
+            current_request: None,
+            history: vec![],
+            join_message: None,
+            validate_against_library: false,
+            duplicate_policy: DuplicatePolicy::default(),
+            required_fields: vec![],
+            auto_close_after: None,
+            auto_close_remaining: None,
+            require_approval: false,
+            pending_approval: vec![],
+            allow_anonymous: false,
+            closed_message: None,
+            paused: false,
+            version: 0,
+            open_at: None,
+            close_at: None,
+            display_max_length: None,
+            filter_profanity: false,
+            max_visible_per_viewer: None,
+            banned_viewer_ids: HashSet::new(),
+            broadcast_rejections: false,
+            song_cooldown_secs: None,
+            finished_instants_by_song_id: HashMap::new(),
+            snapshots: vec![],
+        }
+    }
+}
+
+impl Playlist {
+    /// The playlist as shown to everyone but the streamer. See `Playlist::visible_song_requests`
+    /// and `Playlist::public_song_request`.
+    pub fn public_view(&self) -> Playlist {
+        let public_view = |song_requests: &[SongRequest]| -> Vec<SongRequest> {
+            song_requests
+                .iter()
+                .cloned()
+                .map(|song_request| self.public_song_request(song_request))
+                .collect()
+        };
+
+        Playlist {
+            song_requests: self
+                .visible_song_requests(false)
+                .into_iter()
+                .cloned()
+                .map(|song_request| self.public_song_request(song_request))
+                .collect(),
+            current_request: self
+                .current_request
+                .clone()
+                .map(|song_request| self.public_song_request(song_request)),
+            history: public_view(&self.history),
+            pending_approval: public_view(&self.pending_approval),
+            banned_viewer_ids: HashSet::new(),
+            ..self.clone()
+        }
+    }
+
+    /// The queued requests shown to the streamer, or to everyone else, who only get up to
+    /// `max_visible_per_viewer` of each viewer's. Doesn't hide any fields.
+    pub fn visible_song_requests(&self, is_streamer: bool) -> Vec<&SongRequest> {
+        self.visible_positioned_song_requests(is_streamer)
+            .into_iter()
+            .map(|(_, song_request)| song_request)
+            .collect()
+    }
+
+    /// Like `visible_song_requests`, along with the position of each in the full queue.
+    pub fn visible_positioned_song_requests(
+        &self,
+        is_streamer: bool,
+    ) -> Vec<(usize, &SongRequest)> {
+        let positioned_song_requests = self.song_requests.iter().enumerate();
+
+        let max_visible_per_viewer = match self.max_visible_per_viewer {
+            Some(max_visible_per_viewer) if !is_streamer => max_visible_per_viewer,
+            _ => return positioned_song_requests.collect(),
+        };
+
+        let mut visible_counts_by_viewer_id: HashMap<&str, usize> = HashMap::new();
+
+        positioned_song_requests
+            .filter(|(_, song_request)| {
+                let visible_count = visible_counts_by_viewer_id
+                    .entry(&song_request.viewer_id)
+                    .or_default();
+                *visible_count += 1;
+                *visible_count <= max_visible_per_viewer
+            })
+            .collect()
+    }
+
+    /// A request as shown to everyone but the streamer: without the fields the viewer hid, with
+    /// profanity masked when `filter_profanity` is on, and with usernames and notes cut down to
+    /// `display_max_length`.
+    pub fn public_song_request(&self, song_request: SongRequest) -> SongRequest {
+        let mut song_request = song_request.public_view();
+
+        // Masked before truncating, so a cut can't leave part of a listed word unmasked.
+        if let Some(profanity_filter) = profanity_filter().filter(|_| self.filter_profanity) {
+            profanity_filter.mask(&mut song_request.viewer_username);
+
+            if let Some(note) = &mut song_request.note {
+                profanity_filter.mask(note);
+            }
+
+            for requester in &mut song_request.requesters {
+                profanity_filter.mask(&mut requester.viewer_username);
+            }
+        }
+
+        if let Some(display_max_length) = self.display_max_length {
+            truncate_for_display(&mut song_request.viewer_username, display_max_length);
+
+            if let Some(note) = &mut song_request.note {
+                truncate_for_display(note, display_max_length);
+            }
+
+            for requester in &mut song_request.requesters {
+                truncate_for_display(&mut requester.viewer_username, display_max_length);
+            }
+        }
+
+        song_request
+    }
+
+    pub fn is_banned(&self, viewer_id: &str) -> bool {
+        self.banned_viewer_ids.contains(viewer_id)
+    }
+
+    /// Open or close song requests if `open_at` or `close_at` passed by `now`, clearing them.
+    /// When both passed, the later one wins. Returns whether requests are enabled now, when
+    /// that changed.
+    pub fn apply_schedule(&mut self, now: Timestamp) -> Option<bool> {
+        let passed_open_at = self.open_at.filter(|open_at| *open_at <= now);
+        let passed_close_at = self.close_at.filter(|close_at| *close_at <= now);
+
+        let song_requests_enabled = match (passed_open_at, passed_close_at) {
+            (Some(open_at), Some(close_at)) => open_at > close_at,
+            (Some(_), None) => true,
+            (None, Some(_)) => false,
+            (None, None) => return None,
+        };
+
+        if passed_open_at.is_some() {
+            self.open_at = None;
+        }
+
+        if passed_close_at.is_some() {
+            self.close_at = None;
+        }
+
+        if self.song_requests_enabled == song_requests_enabled {
+            return None;
+        }
+
+        self.song_requests_enabled = song_requests_enabled;
+        Some(song_requests_enabled)
+    }
+
+    /// Start a fresh session: drop the queue, requests awaiting approval, the current request,
+    /// the history and running song cooldowns. Settings, bans, pausing and snapshots are kept.
+    pub fn reset(&mut self) {
+        self.song_requests.clear();
+        self.pending_approval.clear();
+        self.current_request = None;
+        self.history.clear();
+        self.finished_instants_by_song_id.clear();
+    }
+
+    /// Start the song cooldown for `song_id`, if the playlist has one.
+    /// Cooldowns that are over are pruned here.
+    pub fn record_finished_song(&mut self, song_id: &str) {
+        if let Some(song_cooldown_secs) = self.song_cooldown_secs {
+            let cooldown = Duration::from_secs(song_cooldown_secs);
+            let now = Instant::now();
+            self.finished_instants_by_song_id
+                .retain(|_, finished_at| now.duration_since(*finished_at) < cooldown);
+            self.finished_instants_by_song_id
+                .insert(song_id.to_owned(), now);
+        }
+    }
+
+    /// Fail with `ApiError::SongCoolingDown` while `song_id` is still cooling down.
+    pub fn check_song_cooldown(&self, song_id: &str) -> Result<(), ApiError> {
+        let cooldown = match self.song_cooldown_secs {
+            Some(song_cooldown_secs) => Duration::from_secs(song_cooldown_secs),
+            None => return Ok(()),
+        };
+
+        match self.finished_instants_by_song_id.get(song_id) {
+            Some(finished_at) if finished_at.elapsed() < cooldown => {
+                Err(ApiError::SongCoolingDown {
+                    retry_after: cooldown.saturating_sub(finished_at.elapsed()),
+                })
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Cut `text` down to `max_length` characters, ending it with an ellipsis when it was longer.
+fn truncate_for_display(text: &mut String, max_length: usize) {
+    if text.chars().count() > max_length {
+        *text = text
+            .chars()
+            .take(max_length.saturating_sub(1))
+            .chain(std::iter::once('…'))
+            .collect();
+    }
+}
+
+/// Optional song request fields a streamer can make mandatory.
+#[derive(Deserialize, Serialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub enum RequestField {
+    Note,
+    Arrangement,
+    Difficulty,
+    Username,
+}
+
+impl RequestField {
+    /// Name of the field, matching its serde representation.
+    fn as_str(&self) -> &'static str {
+        match self {
+            RequestField::Note => "note",
+            RequestField::Arrangement => "arrangement",
+            RequestField::Difficulty => "difficulty",
+            RequestField::Username => "username",
+        }
+    }
+
+    /// Name of the song request body field this refers to.
+    pub fn body_field_name(&self) -> &'static str {
+        match self {
+            RequestField::Note => "note",
+            RequestField::Arrangement => "arrangement",
+            RequestField::Difficulty => "difficulty",
+            RequestField::Username => "viewerUsername",
+        }
+    }
+}
+
+impl fmt::Display for RequestField {
+    fn fmt(&self, formatter: &mut fmt::Formatter<'_>) -> fmt::Result {
+        formatter.write_str(self.as_str())
+    }
+}
+
+#[derive(Deserialize, Serialize, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "camelCase")]
+pub enum DuplicatePolicy {
+    /// Several viewers may each queue their own request for the same song.
+    #[default]
+    Allow,
+    /// Later requests for a queued song are merged into the existing entry as extra requesters.
+    Merge,
+    /// Only the first request for a song is queued, later ones are rejected.
+    Reject,
 }
 
 #[derive(Deserialize, Serialize, Clone, PartialEq)]
@@ -39,46 +645,474 @@ pub enum ArrangementType {
     Drums,
 }
 
+impl ArrangementType {
+    const ALL: [ArrangementType; 5] = [
+        ArrangementType::Lead,
+        ArrangementType::Rhythm,
+        ArrangementType::Bass,
+        ArrangementType::Vocals,
+        ArrangementType::Drums,
+    ];
+
+    /// Name of the arrangement, matching its serde representation.
+    fn as_str(&self) -> &'static str {
+        match self {
+            ArrangementType::Lead => "Lead",
+            ArrangementType::Rhythm => "Rhythm",
+            ArrangementType::Bass => "Bass",
+            ArrangementType::Vocals => "Vocals",
+            ArrangementType::Drums => "Drums",
+        }
+    }
+}
+
+impl fmt::Display for ArrangementType {
+    fn fmt(&self, formatter: &mut fmt::Formatter<'_>) -> fmt::Result {
+        formatter.write_str(self.as_str())
+    }
+}
+
+/// Difficulty a viewer wants the song played at, for rhythm games that chart several.
+#[derive(Deserialize, Serialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "PascalCase")]
+pub enum Difficulty {
+    Easy,
+    Medium,
+    Hard,
+    Expert,
+}
+
+#[derive(Debug)]
+pub struct ParseArrangementTypeError(String);
+
+impl fmt::Display for ParseArrangementTypeError {
+    fn fmt(&self, formatter: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(formatter, "Unknown arrangement type: {:?}", self.0)
+    }
+}
+
+/// Parses arrangement names case-insensitively, so `bass` and `Bass` are both accepted.
+impl FromStr for ArrangementType {
+    type Err = ParseArrangementTypeError;
+
+    fn from_str(string: &str) -> Result<Self, Self::Err> {
+        ArrangementType::ALL
+            .iter()
+            .find(|arrangement| arrangement.as_str().eq_ignore_ascii_case(string))
+            .cloned()
+            .ok_or_else(|| ParseArrangementTypeError(string.to_owned()))
+    }
+}
+
 #[derive(Deserialize, Serialize, Clone, PartialEq)]
 #[serde(rename_all = "camelCase")]
 pub struct SongRequest {
+    /// Server-generated id that identifies this request, unlike `song_id`, which several
+    /// viewers may share.
+    #[serde(default)]
+    id: String,
+    /// Empty for anonymous requests until the server assigns an `anon-` id.
+    #[serde(default)]
     viewer_id: String,
+    /// Left out of public views when `show_username` is off.
+    #[serde(default, skip_serializing_if = "String::is_empty")]
     viewer_username: String,
     song_id: String,
+    /// Title of the song, filled in from the song library when it lists `song_id`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    song_title: Option<String>,
+    /// Artist of the song, filled in from the song library when it lists `song_id`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    artist: Option<String>,
+    /// Arrangement the viewer wants played, if they have a preference.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    arrangement: Option<ArrangementType>,
+    /// Difficulty the viewer wants played, if they have a preference. Requests for the same
+    /// song are duplicates whatever their difficulty.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    difficulty: Option<Difficulty>,
+    /// When the server accepted the request.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    requested_at: Option<Timestamp>,
+    /// When the request started playing.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    played_at: Option<Timestamp>,
+    /// Free-form message from the viewer to the streamer.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    note: Option<String>,
+    /// Other viewers who requested the same song, when it was merged into this request.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    requesters: Vec<Requester>,
+    /// Viewer id of whoever gifted this request to `viewer_id`, for gift requests.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    gifted_by: Option<String>,
+    /// Whether viewers other than the streamer get to see the username, e.g. on the overlay.
+    #[serde(default = "default_true")]
+    show_username: bool,
+    /// Whether viewers other than the streamer get to see the note.
+    #[serde(default = "default_true")]
+    show_note: bool,
+}
+
+fn default_true() -> bool {
+    true
+}
+
+#[derive(Deserialize, Serialize, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct Requester {
+    viewer_id: String,
+    viewer_username: String,
+    /// Whether viewers other than the streamer get to see the username, as the requester chose.
+    #[serde(default = "default_true")]
+    show_username: bool,
+}
+
+impl SongRequest {
+    /// Username lowercased and trimmed, used when grouping requests by viewer name.
+    /// The username itself is kept as submitted for display.
+    pub fn normalized_viewer_username(&self) -> String {
+        self.viewer_username.trim().to_lowercase()
+    }
+
+    /// The same request with its timestamps expressed at `utc_offset`, for display.
+    pub fn to_offset(mut self, utc_offset: UtcOffset) -> SongRequest {
+        self.requested_at = self
+            .requested_at
+            .map(|requested_at| requested_at.to_offset(utc_offset));
+        self.played_at = self
+            .played_at
+            .map(|played_at| played_at.to_offset(utc_offset));
+        self
+    }
+
+    /// Fill in the title and artist of the requested song from `song_library`, so clients can
+    /// display the request without looking the song up. Requests for songs the library doesn't
+    /// list are left as they are.
+    pub fn fill_song_details(&mut self, song_library: &SongLibrary) {
+        if let Some(song) = song_library.song(&self.song_id) {
+            self.song_title = Some(song.title.to_owned()).filter(|title| !title.is_empty());
+            self.artist = Some(song.artist.to_owned()).filter(|artist| !artist.is_empty());
+        }
+    }
+
+    /// Viewer whose per-viewer limits the request counts against: the gifter for gift
+    /// requests, so gifting can't be used to get around them.
+    pub fn quota_viewer_id(&self) -> &str {
+        self.gifted_by.as_deref().unwrap_or(&self.viewer_id)
+    }
+
+    /// The request as shown to everyone but the streamer, without the fields the viewer hid.
+    pub fn public_view(mut self) -> SongRequest {
+        if !self.show_username {
+            self.viewer_username = String::new();
+        }
+
+        if !self.show_note {
+            self.note = None;
+        }
+
+        for requester in &mut self.requesters {
+            if !requester.show_username {
+                requester.viewer_username = String::new();
+            }
+        }
+
+        self
+    }
+
+    /// Required fields that were left out or blank in this request.
+    pub fn missing_fields(&self, required_fields: &[RequestField]) -> Vec<RequestField> {
+        required_fields
+            .iter()
+            .copied()
+            .filter(|required_field| match required_field {
+                RequestField::Note => self
+                    .note
+                    .as_deref()
+                    .is_none_or(|note| note.trim().is_empty()),
+                RequestField::Arrangement => self.arrangement.is_none(),
+                RequestField::Difficulty => self.difficulty.is_none(),
+                RequestField::Username => self.viewer_username.trim().is_empty(),
+            })
+            .collect()
+    }
+
+    /// Viewers who asked for the song: the original requester and the ones merged in.
+    pub fn requester_count(&self) -> usize {
+        1 + self.requesters.len()
+    }
+
+    /// Whether the viewer behind `other` already requested the same song, either as the
+    /// original requester or as one merged into this request.
+    pub fn is_requested_by(&self, other: &SongRequest) -> bool {
+        let other_normalized_viewer_username = other.normalized_viewer_username();
+
+        let is_same_viewer = |viewer_id: &str, viewer_username: &str| {
+            viewer_id == other.viewer_id
+                && viewer_username.trim().to_lowercase() == other_normalized_viewer_username
+        };
+
+        self.song_id == other.song_id
+            && (is_same_viewer(&self.viewer_id, &self.viewer_username)
+                || self.requesters.iter().any(|requester| {
+                    is_same_viewer(&requester.viewer_id, &requester.viewer_username)
+                }))
+    }
+}
+
+/// Configure logging from `LOG_LEVEL` and `LOG_FORMAT`. `RUST_LOG` is applied on top of
+/// `LOG_LEVEL`, so it can still override the level of specific targets.
+fn init_logger(config: &Config) {
+    logger_builder(config, env::var("RUST_LOG").ok().as_deref()).init();
+}
+
+/// The logger `init_logger` installs, with `RUST_LOG` passed in rather than read here.
+fn logger_builder(config: &Config, rust_log: Option<&str>) -> env_logger::Builder {
+    let mut builder = env_logger::Builder::new();
+
+    if let Some(log_level) = &config.log_level {
+        builder.parse_filters(log_level);
+    }
+
+    if let Some(rust_log) = rust_log {
+        builder.parse_filters(rust_log);
+    }
+
+    if config.json_logs {
+        builder.format(|formatter, record| {
+            writeln!(
+                formatter,
+                "{}",
+                serde_json::json!({
+                    "timestamp": formatter.timestamp().to_string(),
+                    "level": record.level().to_string(),
+                    "target": record.target(),
+                    "message": record.args().to_string(),
+                })
+            )
+        });
+    }
+
+    builder
+}
+
+/// Register every API route, along with the 405 responses for the methods they don't allow.
+fn api_routes(service_config: &mut web::ServiceConfig) {
+    service_config
+        .service(list_rooms_service)
+        .service(list_songs)
+        .service(song_library_info_service)
+        .service(update_playlist)
+        .service(toggle_arrangement_service)
+        .service(list_song_requests_service)
+        .service(list_song_request_viewers_service)
+        .service(list_upcoming_song_requests_service)
+        .service(viewer_position_service)
+        .service(peek_song_request_service)
+        .service(count_song_requests_service)
+        .service(list_song_requests_by_arrangement_service)
+        .service(viewer_ban_service)
+        .service(ban_viewer_service)
+        .service(unban_viewer_service)
+        .service(export_song_requests_service)
+        .service(history_csv_service)
+        .service(leaderboard_service)
+        .service(create_snapshot_service)
+        .service(get_snapshot_service)
+        .service(create_song_request_service)
+        .service(advance_song_requests_service)
+        .service(pause_song_requests_service)
+        .service(reorder_song_request_relative_service)
+        .service(reroll_song_requests_service)
+        .service(reset_playlist_service)
+        .service(replace_song_requests_service)
+        .service(bump_song_request_service)
+        .service(edit_song_request_service)
+        .service(approve_song_request_service)
+        .service(reject_song_request_service)
+        .service(delete_song_requests_service)
+        // Registered before the `{song_id}` delete, which would match its path too.
+        .service(reset_leaderboard_service)
+        .service(delete_song_request_service)
+        .service(delete_song_request_by_id_service)
+        .service(delete_viewer_song_requests_service)
+        .service(websocket_service)
+        .service(events_service)
+        .service(poll_song_requests_service)
+        .service(enable_panic_mode_service)
+        .service(disable_panic_mode_service)
+        .service(merge_playlists_service)
+        .service(list_sessions_service)
+        .service(get_config_service)
+        .service(metrics_service);
+
+    method_not_allowed_routes(service_config);
 }
 
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
-    env_logger::init();
+    let config = web::Data::new(Config::from_env());
+    init_logger(&config);
+
+    if let Some(profanity_word_list_file) = &config.profanity_word_list_file {
+        ProfanityFilter::load(profanity_word_list_file)?.install();
+    }
+
+    let port = config.port;
+
+    let persistence = config
+        .state_file
+        .to_owned()
+        .map(|state_file| Arc::new(Persistence::new(state_file)));
 
-    let port = env::var("PORT")
-        .unwrap_or_else(|_| "8080".to_string())
-        .parse()
-        .expect("PORT must be a number");
+    let song_requests_by_user_id = match &persistence {
+        Some(persistence) => persistence.load()?,
+        None => HashMap::new(),
+    };
 
-    let app_state = web::Data::new(Mutex::new(AppState {
-        song_requests_by_user_id: HashMap::new(),
-    }));
+    let app_state = web::Data::new(InstrumentedMutex::new(AppState::new(
+        song_requests_by_user_id,
+        &config,
+    )));
 
-    let websocket_server_actor_address =
-        websocket_server_actor::WebsocketServerActor::new(app_state.clone()).start();
+    let panic_mode = web::Data::new(PanicMode::default());
+    let song_library_source = web::Data::from(song_library_source(&config.library_source));
+    let viewer_identity_provider =
+        web::Data::from(viewer_identity_provider(config.viewer_auth_url.as_deref()));
+
+    if let Some(persistence) = &persistence {
+        persistence
+            .clone()
+            .spawn_flusher(app_state.clone(), config.state_flush_interval);
+    }
+
+    let websocket_server_actor_address = websocket_server_actor::WebsocketServerActor::new(
+        app_state.clone(),
+        persistence.clone(),
+        config.resume_grace_period,
+        config.broadcast_interval,
+        config.connection_webhook_url.to_owned(),
+    )
+    .start();
+    let shutdown_app_state = app_state.clone();
 
     HttpServer::new(move || {
         let cors = Cors::permissive();
 
         App::new()
+            // CORS wraps compression, so preflight requests are answered before reaching it.
+            .wrap(middleware::Compress::default())
             .wrap(cors)
+            .wrap(AccessLog)
             .app_data(app_state.clone())
+            .app_data(config.clone())
+            .app_data(panic_mode.clone())
+            .app_data(song_library_source.clone())
+            .app_data(viewer_identity_provider.clone())
+            .app_data(web::JsonConfig::default().limit(config.max_json_bytes))
             .data(websocket_server_actor_address.clone())
-            .service(list_songs)
-            .service(update_playlist)
-            .service(list_song_requests_service)
-            .service(create_song_request_service)
-            .service(delete_song_requests_service)
-            .service(delete_song_request_service)
-            .service(websocket_service)
+            .configure(api_routes)
+            .configure(|service_config| static_files_routes(service_config, &config))
     })
     .bind(("0.0.0.0", port))?
     .run()
-    .await
+    .await?;
+
+    if let Some(persistence) = &persistence {
+        persistence.flush_if_dirty(&shutdown_app_state);
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::TestContext;
+    use serde_json::json;
+
+    #[test]
+    fn arrangement_types_round_trip_through_strings_and_serde() {
+        for arrangement in &ArrangementType::ALL {
+            let name = arrangement.to_string();
+
+            assert!(name.parse::<ArrangementType>().unwrap() == *arrangement);
+            assert!(name.to_lowercase().parse::<ArrangementType>().unwrap() == *arrangement);
+            assert!(name.to_uppercase().parse::<ArrangementType>().unwrap() == *arrangement);
+            assert_eq!(serde_json::to_value(arrangement).unwrap(), name);
+        }
+    }
+
+    #[test]
+    fn unknown_arrangement_types_are_rejected() {
+        let error = "keytar".parse::<ArrangementType>().err().unwrap();
+
+        assert_eq!(error.to_string(), "Unknown arrangement type: \"keytar\"");
+    }
+
+    #[actix_rt::test]
+    async fn large_responses_are_compressed_when_accepted() {
+        let songs: Vec<_> = (0..1000)
+            .map(|index| json!({ "id": format!("song-{}", index), "title": "Song" }))
+            .collect();
+        let context = TestContext::default()
+            .with_song_libraries(&[("streamer", json!({ "songList": songs }))]);
+        let mut app = test::init_service(
+            App::new()
+                .wrap(middleware::Compress::default())
+                .wrap(Cors::permissive())
+                .configure(|service_config| context.configure(service_config)),
+        )
+        .await;
+
+        let response = test::call_service(
+            &mut app,
+            test::TestRequest::get()
+                .uri("/streamer/songs")
+                .header(http::header::ACCEPT_ENCODING, "gzip")
+                .to_request(),
+        )
+        .await;
+        assert_eq!(response.status(), http::StatusCode::OK);
+        assert_eq!(
+            response
+                .headers()
+                .get(http::header::CONTENT_ENCODING)
+                .unwrap(),
+            "gzip"
+        );
+
+        let response = test::call_service(
+            &mut app,
+            test::TestRequest::get().uri("/streamer/songs").to_request(),
+        )
+        .await;
+        assert!(response
+            .headers()
+            .get(http::header::CONTENT_ENCODING)
+            .is_none());
+    }
+
+    #[test]
+    fn rust_log_overrides_log_level_for_its_targets() {
+        let enabled = |logger: &env_logger::Logger, target: &str, level: log::Level| {
+            logger.matches(&log::Record::builder().target(target).level(level).build())
+        };
+        let mut config = Config::from_env();
+        config.log_level = Some("debug".to_owned());
+
+        let logger = logger_builder(&config, None).build();
+        assert!(enabled(&logger, "audit", log::Level::Debug));
+        assert!(!enabled(&logger, "audit", log::Level::Trace));
+
+        let logger = logger_builder(&config, Some("audit=warn")).build();
+        assert!(!enabled(&logger, "audit", log::Level::Info));
+        assert!(enabled(&logger, "audit", log::Level::Warn));
+        assert!(enabled(
+            &logger,
+            "rs_song_request_server",
+            log::Level::Debug
+        ));
+    }
 }
@@ -0,0 +1,536 @@
+use std::fmt;
+
+use serde::ser::{self, Serialize};
+
+/// Serialize `value` as MessagePack, with structs written as maps keyed by field name so the
+/// result mirrors the JSON representation.
+pub fn to_vec<T: Serialize + ?Sized>(value: &T) -> Result<Vec<u8>, Error> {
+    let mut serializer = Serializer::default();
+    value.serialize(&mut serializer)?;
+    Ok(serializer.output)
+}
+
+#[derive(Debug)]
+pub struct Error(String);
+
+impl fmt::Display for Error {
+    fn fmt(&self, formatter: &mut fmt::Formatter<'_>) -> fmt::Result {
+        formatter.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl ser::Error for Error {
+    fn custom<T: fmt::Display>(message: T) -> Error {
+        Error(message.to_string())
+    }
+}
+
+#[derive(Default)]
+pub struct Serializer {
+    output: Vec<u8>,
+}
+
+impl Serializer {
+    fn write_marker_and_length(
+        &mut self,
+        fix_marker: u8,
+        fix_max: usize,
+        markers: [u8; 2],
+        length: usize,
+    ) {
+        if length <= fix_max {
+            self.output.push(fix_marker | length as u8);
+        } else if length <= u16::MAX as usize {
+            self.output.push(markers[0]);
+            self.output
+                .extend_from_slice(&(length as u16).to_be_bytes());
+        } else {
+            self.output.push(markers[1]);
+            self.output
+                .extend_from_slice(&(length as u32).to_be_bytes());
+        }
+    }
+
+    fn write_array_length(&mut self, length: usize) {
+        self.write_marker_and_length(0x90, 15, [0xdc, 0xdd], length);
+    }
+
+    fn write_map_length(&mut self, length: usize) {
+        self.write_marker_and_length(0x80, 15, [0xde, 0xdf], length);
+    }
+
+    fn write_str(&mut self, value: &str) {
+        if value.len() < 32 {
+            self.output.push(0xa0 | value.len() as u8);
+        } else if value.len() <= u8::MAX as usize {
+            self.output.push(0xd9);
+            self.output.push(value.len() as u8);
+        } else if value.len() <= u16::MAX as usize {
+            self.output.push(0xda);
+            self.output
+                .extend_from_slice(&(value.len() as u16).to_be_bytes());
+        } else {
+            self.output.push(0xdb);
+            self.output
+                .extend_from_slice(&(value.len() as u32).to_be_bytes());
+        }
+
+        self.output.extend_from_slice(value.as_bytes());
+    }
+
+    fn write_unsigned(&mut self, value: u64) {
+        if value < 0x80 {
+            self.output.push(value as u8);
+        } else if value <= u8::MAX as u64 {
+            self.output.push(0xcc);
+            self.output.push(value as u8);
+        } else if value <= u16::MAX as u64 {
+            self.output.push(0xcd);
+            self.output.extend_from_slice(&(value as u16).to_be_bytes());
+        } else if value <= u32::MAX as u64 {
+            self.output.push(0xce);
+            self.output.extend_from_slice(&(value as u32).to_be_bytes());
+        } else {
+            self.output.push(0xcf);
+            self.output.extend_from_slice(&value.to_be_bytes());
+        }
+    }
+
+    fn write_signed(&mut self, value: i64) {
+        if value >= 0 {
+            self.write_unsigned(value as u64);
+        } else if value >= -32 {
+            self.output.push(value as u8);
+        } else if value >= i8::MIN as i64 {
+            self.output.push(0xd0);
+            self.output.push(value as u8);
+        } else if value >= i16::MIN as i64 {
+            self.output.push(0xd1);
+            self.output.extend_from_slice(&(value as i16).to_be_bytes());
+        } else if value >= i32::MIN as i64 {
+            self.output.push(0xd2);
+            self.output.extend_from_slice(&(value as i32).to_be_bytes());
+        } else {
+            self.output.push(0xd3);
+            self.output.extend_from_slice(&value.to_be_bytes());
+        }
+    }
+
+    /// Start a compound value, wrapped in a single entry map keyed by `variant` for enum variants.
+    fn compound(&mut self, kind: CompoundKind, variant: Option<&'static str>) -> Compound<'_> {
+        Compound {
+            serializer: self,
+            elements: Serializer::default(),
+            count: 0,
+            kind,
+            variant,
+        }
+    }
+}
+
+enum CompoundKind {
+    Array,
+    Map,
+}
+
+/// Elements are buffered until the end, since the length prefix isn't always known upfront.
+pub struct Compound<'a> {
+    serializer: &'a mut Serializer,
+    elements: Serializer,
+    count: usize,
+    kind: CompoundKind,
+    variant: Option<&'static str>,
+}
+
+impl Compound<'_> {
+    fn element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+        self.count += 1;
+        value.serialize(&mut self.elements)
+    }
+
+    fn field<T: Serialize + ?Sized>(&mut self, key: &'static str, value: &T) -> Result<(), Error> {
+        self.count += 1;
+        self.elements.write_str(key);
+        value.serialize(&mut self.elements)
+    }
+
+    fn finish(self) -> Result<(), Error> {
+        if let Some(variant) = self.variant {
+            self.serializer.write_map_length(1);
+            self.serializer.write_str(variant);
+        }
+
+        match self.kind {
+            CompoundKind::Array => self.serializer.write_array_length(self.count),
+            CompoundKind::Map => self.serializer.write_map_length(self.count),
+        }
+
+        self.serializer.output.extend(self.elements.output);
+        Ok(())
+    }
+}
+
+impl<'a> ser::Serializer for &'a mut Serializer {
+    type Ok = ();
+    type Error = Error;
+    type SerializeSeq = Compound<'a>;
+    type SerializeTuple = Compound<'a>;
+    type SerializeTupleStruct = Compound<'a>;
+    type SerializeTupleVariant = Compound<'a>;
+    type SerializeMap = Compound<'a>;
+    type SerializeStruct = Compound<'a>;
+    type SerializeStructVariant = Compound<'a>;
+
+    fn serialize_bool(self, value: bool) -> Result<(), Error> {
+        self.output.push(if value { 0xc3 } else { 0xc2 });
+        Ok(())
+    }
+
+    fn serialize_i8(self, value: i8) -> Result<(), Error> {
+        self.serialize_i64(value as i64)
+    }
+
+    fn serialize_i16(self, value: i16) -> Result<(), Error> {
+        self.serialize_i64(value as i64)
+    }
+
+    fn serialize_i32(self, value: i32) -> Result<(), Error> {
+        self.serialize_i64(value as i64)
+    }
+
+    fn serialize_i64(self, value: i64) -> Result<(), Error> {
+        self.write_signed(value);
+        Ok(())
+    }
+
+    fn serialize_u8(self, value: u8) -> Result<(), Error> {
+        self.serialize_u64(value as u64)
+    }
+
+    fn serialize_u16(self, value: u16) -> Result<(), Error> {
+        self.serialize_u64(value as u64)
+    }
+
+    fn serialize_u32(self, value: u32) -> Result<(), Error> {
+        self.serialize_u64(value as u64)
+    }
+
+    fn serialize_u64(self, value: u64) -> Result<(), Error> {
+        self.write_unsigned(value);
+        Ok(())
+    }
+
+    fn serialize_f32(self, value: f32) -> Result<(), Error> {
+        self.output.push(0xca);
+        self.output.extend_from_slice(&value.to_be_bytes());
+        Ok(())
+    }
+
+    fn serialize_f64(self, value: f64) -> Result<(), Error> {
+        self.output.push(0xcb);
+        self.output.extend_from_slice(&value.to_be_bytes());
+        Ok(())
+    }
+
+    fn serialize_char(self, value: char) -> Result<(), Error> {
+        self.serialize_str(value.encode_utf8(&mut [0; 4]))
+    }
+
+    fn serialize_str(self, value: &str) -> Result<(), Error> {
+        self.write_str(value);
+        Ok(())
+    }
+
+    fn serialize_bytes(self, value: &[u8]) -> Result<(), Error> {
+        if value.len() <= u8::MAX as usize {
+            self.output.push(0xc4);
+            self.output.push(value.len() as u8);
+        } else if value.len() <= u16::MAX as usize {
+            self.output.push(0xc5);
+            self.output
+                .extend_from_slice(&(value.len() as u16).to_be_bytes());
+        } else {
+            self.output.push(0xc6);
+            self.output
+                .extend_from_slice(&(value.len() as u32).to_be_bytes());
+        }
+
+        self.output.extend_from_slice(value);
+        Ok(())
+    }
+
+    fn serialize_none(self) -> Result<(), Error> {
+        self.serialize_unit()
+    }
+
+    fn serialize_some<T: Serialize + ?Sized>(self, value: &T) -> Result<(), Error> {
+        value.serialize(self)
+    }
+
+    fn serialize_unit(self) -> Result<(), Error> {
+        self.output.push(0xc0);
+        Ok(())
+    }
+
+    fn serialize_unit_struct(self, _: &'static str) -> Result<(), Error> {
+        self.serialize_unit()
+    }
+
+    fn serialize_unit_variant(
+        self,
+        _: &'static str,
+        _: u32,
+        variant: &'static str,
+    ) -> Result<(), Error> {
+        self.serialize_str(variant)
+    }
+
+    fn serialize_newtype_struct<T: Serialize + ?Sized>(
+        self,
+        _: &'static str,
+        value: &T,
+    ) -> Result<(), Error> {
+        value.serialize(self)
+    }
+
+    fn serialize_newtype_variant<T: Serialize + ?Sized>(
+        self,
+        _: &'static str,
+        _: u32,
+        variant: &'static str,
+        value: &T,
+    ) -> Result<(), Error> {
+        self.write_map_length(1);
+        self.write_str(variant);
+        value.serialize(self)
+    }
+
+    fn serialize_seq(self, _: Option<usize>) -> Result<Compound<'a>, Error> {
+        Ok(self.compound(CompoundKind::Array, None))
+    }
+
+    fn serialize_tuple(self, _: usize) -> Result<Compound<'a>, Error> {
+        Ok(self.compound(CompoundKind::Array, None))
+    }
+
+    fn serialize_tuple_struct(self, _: &'static str, _: usize) -> Result<Compound<'a>, Error> {
+        Ok(self.compound(CompoundKind::Array, None))
+    }
+
+    fn serialize_tuple_variant(
+        self,
+        _: &'static str,
+        _: u32,
+        variant: &'static str,
+        _: usize,
+    ) -> Result<Compound<'a>, Error> {
+        Ok(self.compound(CompoundKind::Array, Some(variant)))
+    }
+
+    fn serialize_map(self, _: Option<usize>) -> Result<Compound<'a>, Error> {
+        Ok(self.compound(CompoundKind::Map, None))
+    }
+
+    fn serialize_struct(self, _: &'static str, _: usize) -> Result<Compound<'a>, Error> {
+        Ok(self.compound(CompoundKind::Map, None))
+    }
+
+    fn serialize_struct_variant(
+        self,
+        _: &'static str,
+        _: u32,
+        variant: &'static str,
+        _: usize,
+    ) -> Result<Compound<'a>, Error> {
+        Ok(self.compound(CompoundKind::Map, Some(variant)))
+    }
+}
+
+impl ser::SerializeSeq for Compound<'_> {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+        self.element(value)
+    }
+
+    fn end(self) -> Result<(), Error> {
+        self.finish()
+    }
+}
+
+impl ser::SerializeTuple for Compound<'_> {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+        self.element(value)
+    }
+
+    fn end(self) -> Result<(), Error> {
+        self.finish()
+    }
+}
+
+impl ser::SerializeTupleStruct for Compound<'_> {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+        self.element(value)
+    }
+
+    fn end(self) -> Result<(), Error> {
+        self.finish()
+    }
+}
+
+impl ser::SerializeTupleVariant for Compound<'_> {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+        self.element(value)
+    }
+
+    fn end(self) -> Result<(), Error> {
+        self.finish()
+    }
+}
+
+impl ser::SerializeMap for Compound<'_> {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_key<T: Serialize + ?Sized>(&mut self, key: &T) -> Result<(), Error> {
+        self.element(key)
+    }
+
+    fn serialize_value<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+        value.serialize(&mut self.elements)
+    }
+
+    fn end(self) -> Result<(), Error> {
+        self.finish()
+    }
+}
+
+impl ser::SerializeStruct for Compound<'_> {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_field<T: Serialize + ?Sized>(
+        &mut self,
+        key: &'static str,
+        value: &T,
+    ) -> Result<(), Error> {
+        self.field(key, value)
+    }
+
+    fn end(self) -> Result<(), Error> {
+        self.finish()
+    }
+}
+
+impl ser::SerializeStructVariant for Compound<'_> {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_field<T: Serialize + ?Sized>(
+        &mut self,
+        key: &'static str,
+        value: &T,
+    ) -> Result<(), Error> {
+        self.field(key, value)
+    }
+
+    fn end(self) -> Result<(), Error> {
+        self.finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde::Serialize;
+
+    use super::*;
+
+    #[derive(Serialize)]
+    #[serde(rename_all = "camelCase")]
+    struct Example {
+        song_id: &'static str,
+        count: u16,
+        note: Option<String>,
+        #[serde(skip_serializing_if = "Vec::is_empty")]
+        flags: Vec<bool>,
+    }
+
+    #[derive(Serialize)]
+    enum Event {
+        Closed,
+        Notice(&'static str),
+    }
+
+    #[test]
+    fn structs_are_written_as_maps_keyed_by_field_name() {
+        let example = Example {
+            song_id: "a",
+            count: 300,
+            note: None,
+            flags: vec![],
+        };
+
+        assert_eq!(
+            to_vec(&example).unwrap(),
+            [
+                &[0x83, 0xa6][..],
+                b"songId",
+                &[0xa1, b'a', 0xa5],
+                b"count",
+                &[0xcd, 0x01, 0x2c, 0xa4],
+                b"note",
+                &[0xc0],
+            ]
+            .concat()
+        );
+    }
+
+    #[test]
+    fn integers_use_the_smallest_encoding() {
+        assert_eq!(to_vec(&127u64).unwrap(), [0x7f]);
+        assert_eq!(to_vec(&128u64).unwrap(), [0xcc, 0x80]);
+        assert_eq!(to_vec(&70_000u64).unwrap(), [0xce, 0x00, 0x01, 0x11, 0x70]);
+        assert_eq!(to_vec(&-1i64).unwrap(), [0xff]);
+        assert_eq!(to_vec(&-33i64).unwrap(), [0xd0, 0xdf]);
+        assert_eq!(to_vec(&-200i64).unwrap(), [0xd1, 0xff, 0x38]);
+    }
+
+    #[test]
+    fn strings_and_arrays_switch_to_length_prefixes_when_long() {
+        let long_string = "x".repeat(40);
+        assert_eq!(
+            to_vec(&long_string).unwrap(),
+            [&[0xd9, 40][..], long_string.as_bytes()].concat()
+        );
+
+        let long_array = vec![true; 16];
+        assert_eq!(
+            to_vec(&long_array).unwrap(),
+            [&[0xdc, 0x00, 0x10][..], &[0xc3; 16]].concat()
+        );
+    }
+
+    #[test]
+    fn enum_variants_mirror_their_json_representation() {
+        assert_eq!(
+            to_vec(&Event::Closed).unwrap(),
+            [&[0xa6][..], b"Closed"].concat()
+        );
+        assert_eq!(
+            to_vec(&Event::Notice("hi")).unwrap(),
+            [&[0x81, 0xa6][..], b"Notice", &[0xa2], b"hi"].concat()
+        );
+    }
+}
@@ -0,0 +1,213 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use actix::clock::{interval_at, Instant};
+
+use crate::lock_metrics::InstrumentedMutex;
+use crate::{AppState, Playlist};
+
+/// Writes the serialized playlists to where they're saved.
+pub trait StateWriter: Send + Sync {
+    fn write(&self, path: &Path, contents: &[u8]) -> io::Result<()>;
+}
+
+/// Writes the state file in place, the default.
+pub struct FileStateWriter;
+
+impl StateWriter for FileStateWriter {
+    fn write(&self, path: &Path, contents: &[u8]) -> io::Result<()> {
+        // Write to a temporary file first so a crash mid-write can't corrupt the saved state.
+        let temporary_path = path.with_extension("tmp");
+        fs::write(&temporary_path, contents)?;
+        fs::rename(&temporary_path, path)
+    }
+}
+
+/// Saves playlists to a JSON file. Mutations only mark the state dirty, and a background
+/// task writes it at most once per flush interval, so request bursts don't thrash the disk.
+pub struct Persistence {
+    path: PathBuf,
+    dirty: AtomicBool,
+    state_writer: Box<dyn StateWriter>,
+}
+
+impl Persistence {
+    pub fn new(path: PathBuf) -> Persistence {
+        Persistence::with_state_writer(path, Box::new(FileStateWriter))
+    }
+
+    /// Save through `state_writer` instead of straight to the file, e.g. to count writes.
+    pub fn with_state_writer(path: PathBuf, state_writer: Box<dyn StateWriter>) -> Persistence {
+        Persistence {
+            path,
+            dirty: AtomicBool::new(false),
+            state_writer,
+        }
+    }
+
+    /// Read the saved playlists, or none when nothing was saved yet.
+    pub fn load(&self) -> io::Result<HashMap<String, Playlist>> {
+        match fs::read(&self.path) {
+            Ok(contents) => serde_json::from_slice(&contents)
+                .map_err(|error| io::Error::new(io::ErrorKind::InvalidData, error)),
+            Err(error) if error.kind() == io::ErrorKind::NotFound => Ok(HashMap::new()),
+            Err(error) => Err(error),
+        }
+    }
+
+    pub fn mark_dirty(&self) {
+        self.dirty.store(true, Ordering::SeqCst);
+    }
+
+    /// Write the playlists if they changed since the last flush.
+    pub fn flush_if_dirty(&self, app_state: &InstrumentedMutex<AppState>) {
+        if !self.dirty.swap(false, Ordering::SeqCst) {
+            return;
+        }
+
+        let serialized_playlists =
+            serde_json::to_vec(&app_state.lock().unwrap().song_requests_by_user_id);
+
+        let result = serialized_playlists
+            .map_err(|error| io::Error::new(io::ErrorKind::InvalidData, error))
+            .and_then(|serialized_playlists| {
+                self.state_writer.write(&self.path, &serialized_playlists)
+            });
+
+        match result {
+            Ok(()) => log::debug!("Saved state to {:?}.", self.path),
+            Err(error) => {
+                log::error!("Failed to save state to {:?}: {}", self.path, error);
+                // Retry on the next flush.
+                self.mark_dirty();
+            }
+        }
+    }
+
+    /// Flush dirty state every `flush_interval` in the background.
+    pub fn spawn_flusher(
+        self: Arc<Self>,
+        app_state: actix_web::web::Data<InstrumentedMutex<AppState>>,
+        flush_interval: Duration,
+    ) {
+        actix::spawn(async move {
+            let mut flush_interval = interval_at(Instant::now() + flush_interval, flush_interval);
+
+            loop {
+                flush_interval.tick().await;
+                self.flush_if_dirty(&app_state);
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::AtomicUsize;
+
+    use actix::clock::delay_for;
+
+    use super::*;
+    use crate::config::Config;
+
+    /// Counts writes instead of touching the disk, failing them while `failing` is set.
+    #[derive(Clone, Default)]
+    struct CountingStateWriter {
+        writes: Arc<AtomicUsize>,
+        failing: Arc<AtomicBool>,
+    }
+
+    impl StateWriter for CountingStateWriter {
+        fn write(&self, _: &Path, _: &[u8]) -> io::Result<()> {
+            if self.failing.load(Ordering::SeqCst) {
+                return Err(io::Error::other("disk full"));
+            }
+
+            self.writes.fetch_add(1, Ordering::SeqCst);
+            Ok(())
+        }
+    }
+
+    fn app_state() -> actix_web::web::Data<InstrumentedMutex<AppState>> {
+        actix_web::web::Data::new(InstrumentedMutex::new(AppState::new(
+            HashMap::new(),
+            &Config::from_env(),
+        )))
+    }
+
+    #[test]
+    fn flushing_writes_only_when_dirty() {
+        let state_writer = CountingStateWriter::default();
+        let persistence = Persistence::with_state_writer(
+            PathBuf::from("state.json"),
+            Box::new(state_writer.clone()),
+        );
+        let app_state = app_state();
+
+        persistence.flush_if_dirty(&app_state);
+        assert_eq!(state_writer.writes.load(Ordering::SeqCst), 0);
+
+        for _ in 0..100 {
+            persistence.mark_dirty();
+        }
+        persistence.flush_if_dirty(&app_state);
+        persistence.flush_if_dirty(&app_state);
+        assert_eq!(state_writer.writes.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn failed_flushes_are_retried() {
+        let state_writer = CountingStateWriter::default();
+        let persistence = Persistence::with_state_writer(
+            PathBuf::from("state.json"),
+            Box::new(state_writer.clone()),
+        );
+        let app_state = app_state();
+
+        state_writer.failing.store(true, Ordering::SeqCst);
+        persistence.mark_dirty();
+        persistence.flush_if_dirty(&app_state);
+        assert_eq!(state_writer.writes.load(Ordering::SeqCst), 0);
+
+        state_writer.failing.store(false, Ordering::SeqCst);
+        persistence.flush_if_dirty(&app_state);
+        assert_eq!(state_writer.writes.load(Ordering::SeqCst), 1);
+    }
+
+    #[actix_rt::test]
+    async fn rapid_mutations_are_written_at_most_once_per_interval() {
+        let state_writer = CountingStateWriter::default();
+        let persistence = Arc::new(Persistence::with_state_writer(
+            PathBuf::from("state.json"),
+            Box::new(state_writer.clone()),
+        ));
+        let flush_interval = Duration::from_millis(100);
+        let started_at = Instant::now();
+        persistence
+            .clone()
+            .spawn_flusher(app_state(), flush_interval);
+
+        for _ in 0..50 {
+            persistence.mark_dirty();
+            delay_for(Duration::from_millis(5)).await;
+        }
+        delay_for(Duration::from_millis(150)).await;
+
+        // Timers run late on a busy machine, so the bound follows the time that actually passed.
+        let max_writes = (started_at.elapsed().as_millis() / flush_interval.as_millis()) as usize;
+        let writes = state_writer.writes.load(Ordering::SeqCst);
+        assert!(writes >= 1, "no writes");
+        assert!(
+            writes <= max_writes,
+            "{} writes in {} flush intervals",
+            writes,
+            max_writes
+        );
+        assert!(writes < 50, "{} writes for 50 mutations", writes);
+    }
+}
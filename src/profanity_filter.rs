@@ -0,0 +1,77 @@
+use std::collections::HashSet;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::sync::OnceLock;
+
+/// Word list loaded from `PROFANITY_WORD_LIST_FILE` at startup.
+static PROFANITY_FILTER: OnceLock<ProfanityFilter> = OnceLock::new();
+
+/// Masks listed words in viewer-provided text shown on stream.
+pub struct ProfanityFilter {
+    /// Lowercased words to mask.
+    words: HashSet<String>,
+}
+
+impl ProfanityFilter {
+    /// Read a word list with one word per line. Blank lines and lines starting with `#` are
+    /// skipped.
+    pub fn load(path: &Path) -> io::Result<ProfanityFilter> {
+        let words = fs::read_to_string(path)?
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .map(str::to_lowercase)
+            .collect();
+
+        Ok(ProfanityFilter { words })
+    }
+
+    /// Make this the filter returned by `profanity_filter`. Only the first call has any effect.
+    pub fn install(self) {
+        let _ = PROFANITY_FILTER.set(self);
+    }
+
+    /// Replace every character of listed words in `text` with `*`. Words are matched whole and
+    /// case-insensitively.
+    pub fn mask(&self, text: &mut String) {
+        if self.words.is_empty() {
+            return;
+        }
+
+        let mut masked = String::with_capacity(text.len());
+        let mut word_start = None;
+
+        for (index, character) in text.char_indices() {
+            if character.is_alphanumeric() {
+                word_start.get_or_insert(index);
+                continue;
+            }
+
+            if let Some(word_start) = word_start.take() {
+                self.push_word(&mut masked, &text[word_start..index]);
+            }
+
+            masked.push(character);
+        }
+
+        if let Some(word_start) = word_start {
+            self.push_word(&mut masked, &text[word_start..]);
+        }
+
+        *text = masked;
+    }
+
+    fn push_word(&self, masked: &mut String, word: &str) {
+        if self.words.contains(&word.to_lowercase()) {
+            masked.extend(word.chars().map(|_| '*'));
+        } else {
+            masked.push_str(word);
+        }
+    }
+}
+
+/// The installed filter, or `None` when no word list was configured.
+pub fn profanity_filter() -> Option<&'static ProfanityFilter> {
+    PROFANITY_FILTER.get()
+}
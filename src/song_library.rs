@@ -0,0 +1,313 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use actix_web::client::Client;
+use actix_web::http::StatusCode;
+use async_trait::async_trait;
+use bytes::Bytes;
+use serde::Deserialize;
+
+use crate::api_error::ApiError;
+use crate::config::LibrarySource;
+use crate::lock_metrics::InstrumentedMutex;
+use crate::AppState;
+
+/// The parts of a `{user_id}.json` song library the server reads.
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SongLibrary {
+    pub song_list: Vec<Song>,
+}
+
+#[derive(Deserialize)]
+pub struct Song {
+    pub id: String,
+    #[serde(default)]
+    pub artist: String,
+    #[serde(default)]
+    pub title: String,
+}
+
+impl SongLibrary {
+    pub fn contains_song(&self, song_id: &str) -> bool {
+        self.song(song_id).is_some()
+    }
+
+    pub fn song(&self, song_id: &str) -> Option<&Song> {
+        self.song_list.iter().find(|song| song.id == song_id)
+    }
+}
+
+/// Where song libraries are read from, selected with `LIBRARY_SOURCE`.
+#[async_trait(?Send)]
+pub trait SongLibrarySource: Send + Sync {
+    /// Raw song library JSON of a user. Fails with `ApiError::NotFound` when there's none.
+    async fn fetch(&self, user_id: &str) -> Result<Bytes, ApiError>;
+
+    /// When the library last changed, if the source can tell without fetching it.
+    /// Libraries of sources that can't are cached for `UNVERSIONED_SONG_LIBRARY_TTL`.
+    async fn last_modified(&self, user_id: &str) -> Result<Option<SystemTime>, ApiError>;
+}
+
+/// Reads `{user_id}.json` files from the working directory.
+pub struct FileSongLibrarySource;
+
+#[async_trait(?Send)]
+impl SongLibrarySource for FileSongLibrarySource {
+    async fn fetch(&self, user_id: &str) -> Result<Bytes, ApiError> {
+        fs::read(song_library_path(user_id))
+            .map(Bytes::from)
+            .map_err(|_| ApiError::NotFound)
+    }
+
+    async fn last_modified(&self, user_id: &str) -> Result<Option<SystemTime>, ApiError> {
+        let metadata = fs::metadata(song_library_path(user_id)).map_err(|_| ApiError::NotFound)?;
+        Ok(Some(metadata.modified().unwrap_or(UNIX_EPOCH)))
+    }
+}
+
+/// Largest song library accepted from an HTTP source, in bytes.
+const MAX_HTTP_SONG_LIBRARY_BYTES: usize = 16 * 1024 * 1024;
+
+/// Fetches `{base_url}/{user_id}.json`, e.g. from object storage.
+pub struct HttpSongLibrarySource {
+    pub base_url: String,
+}
+
+#[async_trait(?Send)]
+impl SongLibrarySource for HttpSongLibrarySource {
+    async fn fetch(&self, user_id: &str) -> Result<Bytes, ApiError> {
+        let url = format!("{}/{}.json", self.base_url.trim_end_matches('/'), user_id);
+        let mut response = Client::default().get(&url).send().await.map_err(|error| {
+            ApiError::Internal(format!("Failed to fetch song library: {}", error))
+        })?;
+
+        match response.status() {
+            StatusCode::NOT_FOUND => Err(ApiError::NotFound),
+            status if !status.is_success() => Err(ApiError::Internal(format!(
+                "Song library source responded with {}",
+                status
+            ))),
+            _ => response
+                .body()
+                .limit(MAX_HTTP_SONG_LIBRARY_BYTES)
+                .await
+                .map_err(|error| {
+                    ApiError::Internal(format!("Failed to read song library: {}", error))
+                }),
+        }
+    }
+
+    async fn last_modified(&self, _: &str) -> Result<Option<SystemTime>, ApiError> {
+        Ok(None)
+    }
+}
+
+/// Build the song library source selected in the config.
+pub fn song_library_source(library_source: &LibrarySource) -> Arc<dyn SongLibrarySource> {
+    match library_source {
+        LibrarySource::File => Arc::new(FileSongLibrarySource),
+        LibrarySource::Http { base_url } => Arc::new(HttpSongLibrarySource {
+            base_url: base_url.to_owned(),
+        }),
+    }
+}
+
+/// How long a library is reused when its source can't tell when it last changed.
+const UNVERSIONED_SONG_LIBRARY_TTL: Duration = Duration::from_secs(60);
+
+/// Parsed song libraries, each kept along with what's known about the copy that was read.
+#[derive(Default)]
+pub struct SongLibraryCache {
+    song_libraries_by_user_id: HashMap<String, CachedSongLibrary>,
+}
+
+struct CachedSongLibrary {
+    last_modified: Option<SystemTime>,
+    fetched_at: Instant,
+    size: usize,
+    song_library: Arc<SongLibrary>,
+}
+
+impl CachedSongLibrary {
+    /// Whether the cached copy can stand in for the library the source currently has.
+    fn is_fresh(&self, last_modified: Option<SystemTime>) -> bool {
+        match last_modified {
+            Some(last_modified) => self.last_modified == Some(last_modified),
+            None => self.fetched_at.elapsed() < UNVERSIONED_SONG_LIBRARY_TTL,
+        }
+    }
+}
+
+/// A parsed song library and what's known about where it came from.
+pub struct LoadedSongLibrary {
+    /// Size of the raw library, in bytes.
+    pub size: usize,
+    pub last_modified: Option<SystemTime>,
+    pub song_library: Arc<SongLibrary>,
+}
+
+/// Location of a user's song library file.
+pub fn song_library_path(user_id: &str) -> PathBuf {
+    PathBuf::from(format!("{}.json", user_id))
+}
+
+/// Load a user's song library, reusing the cached copy while the source reports it unchanged,
+/// or for a while when the source can't tell. The library is fetched without holding the app
+/// state lock.
+pub async fn load_song_library(
+    user_id: &str,
+    app_state: &InstrumentedMutex<AppState>,
+    song_library_source: &dyn SongLibrarySource,
+) -> Result<LoadedSongLibrary, ApiError> {
+    let last_modified = song_library_source.last_modified(user_id).await?;
+
+    let cached_song_library = app_state
+        .lock()
+        .unwrap()
+        .song_library_cache
+        .song_libraries_by_user_id
+        .get(user_id)
+        .filter(|cached_song_library| cached_song_library.is_fresh(last_modified))
+        .map(|cached_song_library| {
+            (
+                cached_song_library.size,
+                cached_song_library.song_library.clone(),
+            )
+        });
+
+    if let Some((size, song_library)) = cached_song_library {
+        return Ok(LoadedSongLibrary {
+            size,
+            last_modified,
+            song_library,
+        });
+    }
+
+    let contents = song_library_source.fetch(user_id).await?;
+    let song_library: SongLibrary = serde_json::from_slice(&contents)
+        .map_err(|error| ApiError::Internal(format!("Invalid song library: {}", error)))?;
+    let song_library = Arc::new(song_library);
+
+    app_state
+        .lock()
+        .unwrap()
+        .song_library_cache
+        .song_libraries_by_user_id
+        .insert(
+            user_id.to_owned(),
+            CachedSongLibrary {
+                last_modified,
+                fetched_at: Instant::now(),
+                size: contents.len(),
+                song_library: song_library.clone(),
+            },
+        );
+
+    Ok(LoadedSongLibrary {
+        size: contents.len(),
+        last_modified,
+        song_library,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use actix_web::{web, App, HttpResponse, HttpServer};
+
+    use super::*;
+    use crate::test_support::TestContext;
+
+    /// Like the HTTP source, can't tell when a library changed, and counts its fetches.
+    #[derive(Default)]
+    struct UnversionedSongLibrarySource {
+        fetch_count: AtomicUsize,
+    }
+
+    #[async_trait(?Send)]
+    impl SongLibrarySource for UnversionedSongLibrarySource {
+        async fn fetch(&self, _: &str) -> Result<Bytes, ApiError> {
+            self.fetch_count.fetch_add(1, Ordering::SeqCst);
+            Ok(Bytes::from_static(br#"{"songList":[{"id":"a"}]}"#))
+        }
+
+        async fn last_modified(&self, _: &str) -> Result<Option<SystemTime>, ApiError> {
+            Ok(None)
+        }
+    }
+
+    #[actix_rt::test]
+    async fn http_source_fetches_libraries_under_the_base_url() {
+        let server = HttpServer::new(|| {
+            App::new()
+                .route(
+                    "/libraries/streamer.json",
+                    web::get().to(|| HttpResponse::Ok().body(r#"{"songList":[]}"#)),
+                )
+                .route(
+                    "/libraries/broken.json",
+                    web::get().to(|| HttpResponse::InternalServerError().finish()),
+                )
+        })
+        .workers(1)
+        .bind("127.0.0.1:0")
+        .unwrap();
+        let song_library_source = HttpSongLibrarySource {
+            base_url: format!("http://{}/libraries/", server.addrs()[0]),
+        };
+        let server = server.run();
+
+        assert_eq!(
+            song_library_source.fetch("streamer").await.unwrap(),
+            Bytes::from_static(br#"{"songList":[]}"#)
+        );
+        assert!(matches!(
+            song_library_source.fetch("nobody").await,
+            Err(ApiError::NotFound)
+        ));
+        assert!(matches!(
+            song_library_source.fetch("broken").await,
+            Err(ApiError::Internal(_))
+        ));
+        assert_eq!(
+            song_library_source.last_modified("streamer").await.unwrap(),
+            None
+        );
+
+        server.stop(false).await;
+    }
+
+    #[actix_rt::test]
+    async fn unversioned_libraries_are_cached_for_a_while() {
+        let context = TestContext::default();
+        let song_library_source = UnversionedSongLibrarySource::default();
+
+        for _ in 0..3 {
+            let loaded_song_library =
+                load_song_library("streamer", &context.app_state, &song_library_source)
+                    .await
+                    .unwrap();
+            assert!(loaded_song_library.song_library.contains_song("a"));
+        }
+        assert_eq!(song_library_source.fetch_count.load(Ordering::SeqCst), 1);
+
+        context
+            .app_state
+            .lock()
+            .unwrap()
+            .song_library_cache
+            .song_libraries_by_user_id
+            .get_mut("streamer")
+            .unwrap()
+            .fetched_at -= UNVERSIONED_SONG_LIBRARY_TTL;
+        load_song_library("streamer", &context.app_state, &song_library_source)
+            .await
+            .unwrap();
+        assert_eq!(song_library_source.fetch_count.load(Ordering::SeqCst), 2);
+    }
+}
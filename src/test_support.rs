@@ -0,0 +1,225 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use actix::{Actor, Addr};
+use actix_http::ws::{Frame, ProtocolError};
+use actix_http::Request;
+use actix_web::dev::{Server, Service, ServiceResponse};
+use actix_web::http::StatusCode;
+use actix_web::{test, web, App, Error, HttpServer};
+use async_trait::async_trait;
+use bytes::Bytes;
+use futures::{Stream, StreamExt};
+use serde_json::Value;
+
+use crate::admin_routes::PanicMode;
+use crate::api_error::ApiError;
+use crate::config::Config;
+use crate::lock_metrics::InstrumentedMutex;
+use crate::song_library::{song_library_source, SongLibrarySource};
+use crate::viewer_identity::viewer_identity_provider;
+use crate::websocket_server_actor::{ListRoomsMessage, WebsocketServerActor};
+use crate::AppState;
+
+/// App data the API routes run with in tests: an empty state, no persistence, and a running
+/// websocket server actor. Has to be created inside an actix system, e.g. `#[actix_rt::test]`.
+#[derive(Clone)]
+pub struct TestContext {
+    pub config: web::Data<Config>,
+    pub app_state: web::Data<InstrumentedMutex<AppState>>,
+    pub websocket_server_actor_address: Addr<WebsocketServerActor>,
+    pub song_library_source: web::Data<dyn SongLibrarySource>,
+}
+
+impl TestContext {
+    pub fn new(config: Config) -> TestContext {
+        let app_state = web::Data::new(InstrumentedMutex::new(AppState::new(
+            HashMap::new(),
+            &config,
+        )));
+        let websocket_server_actor_address = WebsocketServerActor::new(
+            app_state.clone(),
+            None,
+            config.resume_grace_period,
+            config.broadcast_interval,
+            None,
+        )
+        .start();
+
+        TestContext {
+            song_library_source: web::Data::from(song_library_source(&config.library_source)),
+            config: web::Data::new(config),
+            app_state,
+            websocket_server_actor_address,
+        }
+    }
+
+    /// Read song libraries from `song_libraries` instead of the configured source.
+    pub fn with_song_libraries(mut self, song_libraries: &[(&str, Value)]) -> TestContext {
+        let song_library_source: Arc<dyn SongLibrarySource> = Arc::new(StubSongLibrarySource {
+            song_libraries_by_user_id: song_libraries
+                .iter()
+                .map(|(user_id, song_library)| {
+                    ((*user_id).to_owned(), Bytes::from(song_library.to_string()))
+                })
+                .collect(),
+        });
+        self.song_library_source = web::Data::from(song_library_source);
+        self
+    }
+
+    /// Register the app data and the API routes, for `App::configure`.
+    pub fn configure(&self, service_config: &mut web::ServiceConfig) {
+        service_config
+            .app_data(self.app_state.clone())
+            .app_data(self.config.clone())
+            .app_data(web::Data::new(PanicMode::default()))
+            .app_data(self.song_library_source.clone())
+            .app_data(web::Data::from(viewer_identity_provider(
+                self.config.viewer_auth_url.as_deref(),
+            )))
+            .app_data(web::JsonConfig::default().limit(self.config.max_json_bytes))
+            .data(self.websocket_server_actor_address.clone());
+
+        crate::api_routes(service_config);
+    }
+
+    /// Wait until the websocket server actor handled everything sent to it so far, e.g. the
+    /// broadcasts that bump playlist versions.
+    pub async fn wait_for_broadcasts(&self) {
+        self.websocket_server_actor_address
+            .send(ListRoomsMessage)
+            .await
+            .unwrap();
+    }
+
+    /// Serve the API routes on a free local port with `workers` threads, for tests that need
+    /// real connections. Returns the running server and its address.
+    pub fn start_server(&self, workers: usize) -> (Server, SocketAddr) {
+        let context = self.clone();
+        let server = HttpServer::new(move || {
+            let context = context.clone();
+            App::new().configure(move |service_config| context.configure(service_config))
+        })
+        .workers(workers)
+        .bind("127.0.0.1:0")
+        .unwrap();
+        let address = server.addrs()[0];
+
+        (server.run(), address)
+    }
+}
+
+impl Default for TestContext {
+    fn default() -> TestContext {
+        TestContext::new(Config::from_env())
+    }
+}
+
+/// Song libraries kept in memory, which never change.
+pub struct StubSongLibrarySource {
+    pub song_libraries_by_user_id: HashMap<String, Bytes>,
+}
+
+#[async_trait(?Send)]
+impl SongLibrarySource for StubSongLibrarySource {
+    async fn fetch(&self, user_id: &str) -> Result<Bytes, ApiError> {
+        self.song_libraries_by_user_id
+            .get(user_id)
+            .cloned()
+            .ok_or(ApiError::NotFound)
+    }
+
+    async fn last_modified(&self, user_id: &str) -> Result<Option<SystemTime>, ApiError> {
+        self.fetch(user_id).await?;
+        Ok(Some(UNIX_EPOCH + Duration::from_secs(1_600_000_000)))
+    }
+}
+
+/// Initialize the API routes of a `TestContext` as a service to call with `send`.
+macro_rules! test_app {
+    ($context:expr) => {
+        actix_web::test::init_service(
+            actix_web::App::new().configure(|service_config| $context.configure(service_config)),
+        )
+        .await
+    };
+}
+
+pub(crate) use test_app;
+
+/// Call the app, returning the status and the body parsed as JSON, or `Value::Null` for
+/// bodies that aren't JSON.
+pub async fn send<S, B>(app: &mut S, request: test::TestRequest) -> (StatusCode, Value)
+where
+    S: Service<Request = Request, Response = ServiceResponse<B>, Error = Error>,
+    B: actix_web::body::MessageBody + Unpin,
+{
+    let response = test::call_service(app, request.to_request()).await;
+    let status = response.status();
+    let body = test::read_body(response).await;
+
+    (status, serde_json::from_slice(&body).unwrap_or(Value::Null))
+}
+
+/// `PUT /{user_id}/songs` with song requests enabled and every arrangement off.
+pub async fn open_playlist<S, B>(app: &mut S, user_id: &str)
+where
+    S: Service<Request = Request, Response = ServiceResponse<B>, Error = Error>,
+    B: actix_web::body::MessageBody + Unpin,
+{
+    let (status, _) = send(
+        app,
+        test::TestRequest::put()
+            .uri(&format!("/{}/songs", user_id))
+            .set_json(&serde_json::json!({ "songRequestsEnabled": true, "songArrangements": [] })),
+    )
+    .await;
+
+    assert_eq!(status, StatusCode::OK);
+}
+
+/// `PUT /{user_id}/songs/requests` with `song_request` as the body.
+pub async fn create_song_request<S, B>(
+    app: &mut S,
+    user_id: &str,
+    song_request: Value,
+) -> (StatusCode, Value)
+where
+    S: Service<Request = Request, Response = ServiceResponse<B>, Error = Error>,
+    B: actix_web::body::MessageBody + Unpin,
+{
+    send(
+        app,
+        test::TestRequest::put()
+            .uri(&format!("/{}/songs/requests", user_id))
+            .set_json(&song_request),
+    )
+    .await
+}
+
+/// Song ids of the requests in a JSON list, in order.
+pub fn song_ids(song_requests: &Value) -> Vec<&str> {
+    song_requests
+        .as_array()
+        .expect("song requests should be a list")
+        .iter()
+        .map(|song_request| song_request["songId"].as_str().unwrap())
+        .collect()
+}
+
+/// The next text message a websocket client receives, skipping pings and other frames.
+pub async fn next_text<S>(connection: &mut S) -> String
+where
+    S: Stream<Item = Result<Frame, ProtocolError>> + Unpin,
+{
+    loop {
+        match connection.next().await {
+            Some(Ok(Frame::Text(text))) => return String::from_utf8(text.to_vec()).unwrap(),
+            Some(Ok(_)) => continue,
+            other => panic!("expected a text message, got {:?}", other),
+        }
+    }
+}
@@ -0,0 +1,99 @@
+use std::time::{Duration, Instant};
+
+/// Leaky bucket capping how many requests are accepted per second, e.g. song requests across
+/// the whole server, to protect what each one fans out to, like persistence and webhooks, or
+/// messages from a single websocket session.
+/// The bucket holds up to a second's worth of requests and refills continuously.
+pub struct ThroughputLimiter {
+    /// Requests allowed per second, or `None` for no limit.
+    rate_per_sec: Option<u32>,
+    /// Requests that can still be accepted right away.
+    level: f64,
+    refilled_at: Instant,
+}
+
+impl ThroughputLimiter {
+    pub fn new(rate_per_sec: Option<u32>) -> ThroughputLimiter {
+        ThroughputLimiter {
+            rate_per_sec,
+            level: f64::from(rate_per_sec.unwrap_or_default()),
+            refilled_at: Instant::now(),
+        }
+    }
+
+    /// Return how long until the next request fits when the bucket is empty, without taking
+    /// a request out of it.
+    pub fn check(&mut self) -> Result<(), Duration> {
+        let rate_per_sec = match self.rate_per_sec {
+            Some(rate_per_sec) => f64::from(rate_per_sec),
+            None => return Ok(()),
+        };
+
+        let now = Instant::now();
+        let elapsed = now.saturating_duration_since(self.refilled_at);
+        self.level = (self.level + elapsed.as_secs_f64() * rate_per_sec).min(rate_per_sec);
+        self.refilled_at = now;
+
+        if self.level < 1.0 {
+            return Err(Duration::from_secs_f64((1.0 - self.level) / rate_per_sec));
+        }
+
+        Ok(())
+    }
+
+    /// Take a request out of the bucket, or return how long until the next one fits when
+    /// it's empty.
+    pub fn acquire(&mut self) -> Result<(), Duration> {
+        self.check()?;
+
+        if self.rate_per_sec.is_some() {
+            self.level -= 1.0;
+        }
+
+        Ok(())
+    }
+
+    /// Pretend `elapsed` more time went by since the bucket was last refilled.
+    #[cfg(test)]
+    pub fn advance(&mut self, elapsed: Duration) {
+        self.refilled_at -= elapsed;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn check_leaves_the_bucket_as_is() {
+        let mut throughput_limiter = ThroughputLimiter::new(Some(1));
+
+        assert!(throughput_limiter.check().is_ok());
+        assert!(throughput_limiter.check().is_ok());
+        assert!(throughput_limiter.acquire().is_ok());
+        assert!(throughput_limiter.check().is_err());
+        assert!(throughput_limiter.acquire().is_err());
+    }
+
+    #[test]
+    fn no_rate_never_limits() {
+        let mut throughput_limiter = ThroughputLimiter::new(None);
+
+        for _ in 0..100 {
+            assert!(throughput_limiter.acquire().is_ok());
+        }
+    }
+
+    #[test]
+    fn empty_buckets_refill_over_time() {
+        let mut throughput_limiter = ThroughputLimiter::new(Some(2));
+
+        assert!(throughput_limiter.acquire().is_ok());
+        assert!(throughput_limiter.acquire().is_ok());
+        assert!(throughput_limiter.acquire().unwrap_err() <= Duration::from_millis(500));
+
+        throughput_limiter.advance(Duration::from_millis(500));
+        assert!(throughput_limiter.acquire().is_ok());
+        assert!(throughput_limiter.acquire().is_err());
+    }
+}
@@ -0,0 +1,130 @@
+use std::fmt;
+
+use serde::de::{self, Deserializer, Visitor};
+use serde::{Deserialize, Serialize, Serializer};
+use time::{Format, OffsetDateTime, UtcOffset};
+
+/// Point in time serialized as an RFC 3339 string, e.g. `2021-06-01T20:15:00+00:00`.
+/// Timestamps are recorded in UTC and only shifted for human-facing output.
+#[derive(Clone, Copy, Debug, PartialEq, PartialOrd)]
+pub struct Timestamp(OffsetDateTime);
+
+impl Timestamp {
+    pub fn now() -> Timestamp {
+        Timestamp(OffsetDateTime::now_utc())
+    }
+
+    /// Whole seconds from `earlier` to this timestamp, or 0 if `earlier` is later.
+    pub fn secs_since(self, earlier: Timestamp) -> u64 {
+        (self.0 - earlier.0).whole_seconds().max(0) as u64
+    }
+
+    /// The same instant, expressed at `utc_offset`.
+    pub fn to_offset(self, utc_offset: UtcOffset) -> Timestamp {
+        Timestamp(self.0.to_offset(utc_offset))
+    }
+}
+
+impl fmt::Display for Timestamp {
+    fn fmt(&self, formatter: &mut fmt::Formatter<'_>) -> fmt::Result {
+        formatter.write_str(&self.0.format(Format::Rfc3339))
+    }
+}
+
+impl Serialize for Timestamp {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for Timestamp {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Timestamp, D::Error> {
+        struct TimestampVisitor;
+
+        impl<'de> Visitor<'de> for TimestampVisitor {
+            type Value = Timestamp;
+
+            fn expecting(&self, formatter: &mut fmt::Formatter<'_>) -> fmt::Result {
+                formatter.write_str("an RFC 3339 timestamp")
+            }
+
+            fn visit_str<E: de::Error>(self, value: &str) -> Result<Timestamp, E> {
+                OffsetDateTime::parse(value, Format::Rfc3339)
+                    .map(Timestamp)
+                    .map_err(E::custom)
+            }
+        }
+
+        deserializer.deserialize_str(TimestampVisitor)
+    }
+}
+
+/// Parse a UTC offset written as `UTC`, `Z` or `±HH:MM`.
+pub fn parse_utc_offset(value: &str) -> Option<UtcOffset> {
+    if value.eq_ignore_ascii_case("utc") || value.eq_ignore_ascii_case("z") {
+        return Some(UtcOffset::UTC);
+    }
+
+    let (sign, value) = match value.as_bytes().first()? {
+        b'+' => (1, &value[1..]),
+        b'-' => (-1, &value[1..]),
+        _ => return None,
+    };
+
+    let (hours, minutes) = value.split_once(':')?;
+    let hours: i16 = hours.parse().ok()?;
+    let minutes: i16 = minutes.parse().ok()?;
+
+    if hours > 23 || minutes > 59 {
+        return None;
+    }
+
+    Some(UtcOffset::minutes(sign * (hours * 60 + minutes)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn timestamps_format_as_rfc_3339_at_their_offset() {
+        let timestamp = Timestamp(OffsetDateTime::from_unix_timestamp(1_622_578_500));
+
+        assert_eq!(timestamp.to_string(), "2021-06-01T20:15:00+00:00");
+        assert_eq!(
+            timestamp
+                .to_offset(parse_utc_offset("-03:00").unwrap())
+                .to_string(),
+            "2021-06-01T17:15:00-03:00"
+        );
+        assert_eq!(
+            timestamp
+                .to_offset(parse_utc_offset("+05:30").unwrap())
+                .to_string(),
+            "2021-06-02T01:45:00+05:30"
+        );
+    }
+
+    #[test]
+    fn timestamps_round_trip_through_serde() {
+        let timestamp = Timestamp(OffsetDateTime::from_unix_timestamp(1_622_578_500));
+        let serialized = serde_json::to_string(&timestamp).unwrap();
+
+        assert_eq!(serialized, "\"2021-06-01T20:15:00+00:00\"");
+        assert_eq!(
+            serde_json::from_str::<Timestamp>(&serialized).unwrap(),
+            timestamp
+        );
+    }
+
+    #[test]
+    fn utc_offsets_parse_from_utc_or_hours_and_minutes() {
+        assert_eq!(parse_utc_offset("UTC"), Some(UtcOffset::UTC));
+        assert_eq!(parse_utc_offset("z"), Some(UtcOffset::UTC));
+        assert_eq!(parse_utc_offset("-03:30"), Some(UtcOffset::minutes(-210)));
+        assert_eq!(parse_utc_offset("+14:00"), Some(UtcOffset::hours(14)));
+        assert_eq!(parse_utc_offset("03:00"), None);
+        assert_eq!(parse_utc_offset("+24:00"), None);
+        assert_eq!(parse_utc_offset("America/Sao_Paulo"), None);
+    }
+}
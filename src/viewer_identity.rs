@@ -0,0 +1,190 @@
+use std::sync::Arc;
+
+use actix_web::client::Client;
+use actix_web::dev::Payload;
+use actix_web::http::StatusCode;
+use actix_web::{web, Error, FromRequest, HttpRequest};
+use async_trait::async_trait;
+use futures::future::LocalBoxFuture;
+use futures::FutureExt;
+use serde::Deserialize;
+
+use crate::api_error::ApiError;
+use crate::SongRequest;
+
+/// Header viewers send the token of their platform account in, when the server verifies
+/// viewer ids.
+pub const VIEWER_TOKEN_HEADER: &str = "X-Viewer-Token";
+
+/// Checks that whoever submits a song request is the viewer it's made for.
+#[async_trait(?Send)]
+pub trait ViewerIdentityProvider: Send + Sync {
+    /// Fails with `ApiError::Unauthorized` unless `token` belongs to `viewer_id`.
+    async fn verify(&self, viewer_id: &str, token: Option<&str>) -> Result<(), ApiError>;
+}
+
+/// Takes viewer ids as sent, for when `VIEWER_AUTH_URL` isn't set.
+pub struct TrustingViewerIdentityProvider;
+
+#[async_trait(?Send)]
+impl ViewerIdentityProvider for TrustingViewerIdentityProvider {
+    async fn verify(&self, _: &str, _: Option<&str>) -> Result<(), ApiError> {
+        Ok(())
+    }
+}
+
+/// Sends the token to `url` as `Authorization: Bearer <token>` and compares the `user_id` it
+/// responds with to the viewer id, like Twitch's `/oauth2/validate` does.
+pub struct HttpViewerIdentityProvider {
+    pub url: String,
+}
+
+#[derive(Deserialize)]
+struct ValidatedToken {
+    user_id: String,
+}
+
+#[async_trait(?Send)]
+impl ViewerIdentityProvider for HttpViewerIdentityProvider {
+    async fn verify(&self, viewer_id: &str, token: Option<&str>) -> Result<(), ApiError> {
+        let token = token.ok_or(ApiError::Unauthorized)?;
+
+        let mut response = Client::default()
+            .get(&self.url)
+            .bearer_auth(token)
+            .send()
+            .await
+            .map_err(|error| {
+                ApiError::Internal(format!(
+                    "Failed to reach the viewer auth provider: {}",
+                    error
+                ))
+            })?;
+
+        match response.status() {
+            StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN => Err(ApiError::Unauthorized),
+            status if !status.is_success() => Err(ApiError::Internal(format!(
+                "Viewer auth provider responded with {}",
+                status
+            ))),
+            _ => {
+                let validated_token: ValidatedToken = response.json().await.map_err(|error| {
+                    ApiError::Internal(format!(
+                        "Invalid response from the viewer auth provider: {}",
+                        error
+                    ))
+                })?;
+
+                if validated_token.user_id == viewer_id {
+                    Ok(())
+                } else {
+                    Err(ApiError::Unauthorized)
+                }
+            }
+        }
+    }
+}
+
+/// Build the viewer identity provider for `VIEWER_AUTH_URL`.
+pub fn viewer_identity_provider(viewer_auth_url: Option<&str>) -> Arc<dyn ViewerIdentityProvider> {
+    match viewer_auth_url {
+        Some(url) => Arc::new(HttpViewerIdentityProvider {
+            url: url.to_owned(),
+        }),
+        None => Arc::new(TrustingViewerIdentityProvider),
+    }
+}
+
+/// Song request body whose viewer id was checked against `VIEWER_TOKEN_HEADER` by the
+/// configured `ViewerIdentityProvider`. Anonymous requests have no id to check and pass as is.
+pub struct VerifiedSongRequest(pub SongRequest);
+
+impl FromRequest for VerifiedSongRequest {
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<VerifiedSongRequest, Error>>;
+    type Config = ();
+
+    fn from_request(request: &HttpRequest, payload: &mut Payload) -> Self::Future {
+        let song_request = web::Json::<SongRequest>::from_request(request, payload);
+        let token = request
+            .headers()
+            .get(VIEWER_TOKEN_HEADER)
+            .and_then(|token| token.to_str().ok())
+            .map(str::to_owned);
+        let viewer_identity_provider = request
+            .app_data::<web::Data<dyn ViewerIdentityProvider>>()
+            .cloned();
+
+        async move {
+            let song_request = song_request.await?.into_inner();
+            let viewer_id = song_request.viewer_id.trim();
+
+            if let Some(viewer_identity_provider) = viewer_identity_provider {
+                if !viewer_id.is_empty() {
+                    viewer_identity_provider
+                        .verify(viewer_id, token.as_deref())
+                        .await?;
+                }
+            }
+
+            Ok(VerifiedSongRequest(song_request))
+        }
+        .boxed_local()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::test::TestRequest;
+    use actix_web::{App, HttpResponse, HttpServer};
+    use serde_json::json;
+
+    use super::*;
+    use crate::config::Config;
+    use crate::test_support::{open_playlist, send, test_app, TestContext};
+
+    /// Stands in for the platform, knowing a single token that belongs to `alice`.
+    async fn validate_token(request: HttpRequest) -> HttpResponse {
+        match request.headers().get("Authorization") {
+            Some(authorization) if authorization == "Bearer alice-token" => {
+                HttpResponse::Ok().json(json!({ "user_id": "alice" }))
+            }
+            _ => HttpResponse::Unauthorized().finish(),
+        }
+    }
+
+    #[actix_rt::test]
+    async fn requests_are_refused_unless_the_token_belongs_to_the_viewer() {
+        let provider =
+            HttpServer::new(|| App::new().route("/validate", web::get().to(validate_token)))
+                .workers(1)
+                .bind("127.0.0.1:0")
+                .unwrap();
+        let mut config = Config::from_env();
+        config.viewer_auth_url = Some(format!("http://{}/validate", provider.addrs()[0]));
+        let provider = provider.run();
+        let context = TestContext::new(config);
+        let mut app = test_app!(context);
+        open_playlist(&mut app, "streamer").await;
+        let request_song = |viewer_id: &str, token: Option<&str>| {
+            let request = TestRequest::put()
+                .uri("/streamer/songs/requests")
+                .set_json(&json!({ "viewerId": viewer_id, "songId": "a" }));
+            match token {
+                Some(token) => request.header(VIEWER_TOKEN_HEADER, token),
+                None => request,
+            }
+        };
+
+        let (status, _) = send(&mut app, request_song("alice", Some("alice-token"))).await;
+        assert_eq!(status, StatusCode::OK);
+        let (status, _) = send(&mut app, request_song("mallory", Some("alice-token"))).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        let (status, _) = send(&mut app, request_song("alice", Some("stolen-token"))).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        let (status, _) = send(&mut app, request_song("alice", None)).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+        provider.stop(false).await;
+    }
+}
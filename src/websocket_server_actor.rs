@@ -1,90 +1,541 @@
 use actix::prelude::*;
+use bytes::Bytes;
+use futures::channel::mpsc::UnboundedSender;
+use futures::channel::oneshot;
 use rand::{self, rngs::ThreadRng, Rng};
 
-use std::sync::Mutex;
+use std::borrow::Cow;
+use std::sync::Arc;
 
 use crate::{AppState, ArrangementType, Playlist, SongRequest};
+use actix_web::client::Client;
 use actix_web::web::Data;
-use std::collections::{HashMap, HashSet};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::io;
+use std::time::{Duration, Instant};
 
-use crate::websocket_session_actor::WebsocketReplyMessage;
+/// Backoff suggested to a client rejected at a connection cap, when the server is just full.
+const CONNECTION_RETRY_BASE: Duration = Duration::from_secs(5);
+/// Longest backoff suggested to a client rejected at a connection cap.
+const MAX_CONNECTION_RETRY: Duration = Duration::from_secs(60);
+/// How long rejected connections keep counting towards the load.
+const CONNECTION_REJECTION_WINDOW: Duration = Duration::from_secs(60);
+
+/// How often idle server-sent event streams get a comment, so proxies don't close them.
+const SSE_KEEP_ALIVE_INTERVAL: Duration = Duration::from_secs(15);
+
+/// How often sessions whose actor died without disconnecting get cleaned up.
+const DEAD_SESSION_REAP_INTERVAL: Duration = Duration::from_secs(60);
+
+/// How often playlists' `open_at` and `close_at` are checked.
+const SCHEDULE_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Sending half of a server-sent event stream.
+pub type SseSender = UnboundedSender<Result<Bytes, io::Error>>;
+
+use crate::lock_metrics::InstrumentedMutex;
+use crate::persistence::Persistence;
+use crate::timestamp::Timestamp;
+use crate::websocket_session_actor::{KickSessionMessage, WebsocketReplyMessage};
 use serde::Serialize;
+use uuid::Uuid;
 
 pub struct WebsocketServerActor {
     recipients_by_session_id: HashMap<usize, Recipient<WebsocketReplyMessage>>,
+    kick_recipients_by_session_id: HashMap<usize, Recipient<KickSessionMessage>>,
     session_ids_by_room_name: HashMap<String, HashSet<usize>>,
+    connected_at_by_session_id: HashMap<usize, Instant>,
+    round_trip_time_by_session_id: HashMap<usize, Duration>,
+    /// Latest app state version each session acknowledged with `/ack`.
+    acked_versions_by_session_id: HashMap<usize, u64>,
+    /// Parts of the app state sessions subscribed to with `/subscribe`. Sessions without an
+    /// entry get all of it.
+    broadcast_interests_by_session_id: HashMap<usize, HashSet<BroadcastInterest>>,
+    /// Read-only clients following a room's app state over server-sent events.
+    sse_senders_by_room_name: HashMap<String, Vec<SseSender>>,
+    /// Sessions authenticated as the streamer of the room they're in, which get the full app
+    /// state instead of its public view.
+    streamer_session_ids: HashSet<usize>,
+    /// Last broadcasted app state of each room, sent to sessions as they enter it so they don't
+    /// have to wait for the next change.
+    last_app_state_by_room_name: HashMap<String, SerializedAppState>,
+    /// When connections were recently rejected for being over a connection cap.
+    rejected_connection_instants: VecDeque<Instant>,
+    /// Long polls waiting for the next change of a room.
+    poll_waiters_by_room_name: HashMap<String, Vec<oneshot::Sender<()>>>,
     random_number_generator: ThreadRng,
-    app_state: Data<Mutex<AppState>>,
+    app_state: Data<InstrumentedMutex<AppState>>,
+    persistence: Option<Arc<Persistence>>,
+    /// Token each connected session can later resume with.
+    resume_tokens_by_session_id: HashMap<usize, String>,
+    /// Context of recently disconnected sessions, kept for `resume_grace_period`.
+    resumable_sessions_by_token: HashMap<String, ResumableSession>,
+    resume_grace_period: Duration,
+    /// Shortest time between two app state broadcasts of a room, if any.
+    broadcast_interval: Option<Duration>,
+    /// When each room's app state was last broadcast, while `broadcast_interval` is set.
+    last_broadcast_instants_by_room_name: HashMap<String, Instant>,
+    /// Rooms with a broadcast scheduled for the end of their `broadcast_interval`.
+    deferred_broadcast_room_names: HashSet<String>,
+    /// URL sessions connecting to and disconnecting from rooms are posted to, if any.
+    connection_webhook_url: Option<String>,
+}
+
+/// Per-session state restored when a client resumes its session after reconnecting.
+#[derive(Clone)]
+pub struct SessionContext {
+    pub room_name: String,
+    pub is_streamer: bool,
+    pub round_trip_time: Option<Duration>,
+    /// Latest app state version the session acknowledged in its room.
+    pub acked_version: Option<u64>,
+    /// Parts of the app state the session subscribed to, or `None` for all of it.
+    pub broadcast_interests: Option<HashSet<BroadcastInterest>>,
+}
+
+struct ResumableSession {
+    session_id: usize,
+    context: SessionContext,
+    disconnected_at: Instant,
 }
 
 impl Actor for WebsocketServerActor {
     type Context = Context<Self>;
+
+    fn started(&mut self, context: &mut Self::Context) {
+        context.run_interval(SSE_KEEP_ALIVE_INTERVAL, |websocket_server_actor, _| {
+            let room_names: Vec<String> = websocket_server_actor
+                .sse_senders_by_room_name
+                .keys()
+                .cloned()
+                .collect();
+
+            for room_name in room_names {
+                websocket_server_actor
+                    .send_sse(&room_name, Bytes::from_static(b": keep-alive\n\n"));
+            }
+        });
+
+        context.run_interval(self.resume_grace_period, |websocket_server_actor, _| {
+            let resume_grace_period = websocket_server_actor.resume_grace_period;
+
+            websocket_server_actor
+                .resumable_sessions_by_token
+                .retain(|_, resumable_session| {
+                    resumable_session.disconnected_at.elapsed() < resume_grace_period
+                });
+        });
+
+        context.run_interval(DEAD_SESSION_REAP_INTERVAL, |websocket_server_actor, _| {
+            websocket_server_actor.reap_dead_sessions();
+        });
+
+        context.run_interval(
+            SCHEDULE_CHECK_INTERVAL,
+            |websocket_server_actor, context| {
+                let now = Timestamp::now();
+                let scheduled_toggles: Vec<(String, bool)> = websocket_server_actor
+                    .app_state
+                    .lock()
+                    .unwrap()
+                    .song_requests_by_user_id
+                    .iter_mut()
+                    .filter_map(|(user_id, playlist)| {
+                        playlist
+                            .apply_schedule(now)
+                            .map(|enabled| (user_id.to_owned(), enabled))
+                    })
+                    .collect();
+
+                for (user_id, enabled) in scheduled_toggles {
+                    context.notify(BroadcastAppStateMessage {
+                        user_id: user_id.to_owned(),
+                    });
+                    context.notify(ScheduledToggleMessage { user_id, enabled });
+                }
+            },
+        );
+    }
 }
 
 impl WebsocketServerActor {
-    pub fn new(state: Data<Mutex<AppState>>) -> WebsocketServerActor {
+    pub fn new(
+        state: Data<InstrumentedMutex<AppState>>,
+        persistence: Option<Arc<Persistence>>,
+        resume_grace_period: Duration,
+        broadcast_interval: Option<Duration>,
+        connection_webhook_url: Option<String>,
+    ) -> WebsocketServerActor {
         WebsocketServerActor {
             recipients_by_session_id: HashMap::new(),
+            kick_recipients_by_session_id: HashMap::new(),
             session_ids_by_room_name: HashMap::new(),
+            connected_at_by_session_id: HashMap::new(),
+            round_trip_time_by_session_id: HashMap::new(),
+            acked_versions_by_session_id: HashMap::new(),
+            broadcast_interests_by_session_id: HashMap::new(),
+            sse_senders_by_room_name: HashMap::new(),
+            streamer_session_ids: HashSet::new(),
+            last_app_state_by_room_name: HashMap::new(),
+            rejected_connection_instants: VecDeque::new(),
+            poll_waiters_by_room_name: HashMap::new(),
             random_number_generator: rand::thread_rng(),
             app_state: state,
+            persistence,
+            resume_tokens_by_session_id: HashMap::new(),
+            resumable_sessions_by_token: HashMap::new(),
+            resume_grace_period,
+            broadcast_interval,
+            last_broadcast_instants_by_room_name: HashMap::new(),
+            deferred_broadcast_room_names: HashSet::new(),
+            connection_webhook_url,
         }
     }
 }
 
 impl WebsocketServerActor {
-    /// Send message to all client sessions in the room.
-    fn send_message(&self, room_name: &str, message: &str, skip_session_id: usize) {
-        if let Some(session_ids) = self.session_ids_by_room_name.get(room_name) {
-            for session_id in session_ids {
-                if *session_id != skip_session_id {
-                    if let Some(reply_message_recipient) =
-                        self.recipients_by_session_id.get(session_id)
-                    {
-                        reply_message_recipient
-                            .do_send(WebsocketReplyMessage {
-                                message: message.to_owned(),
-                            })
-                            .unwrap();
-                    }
+    /// Post a connection event of `room_name` to the connection webhook, if one is configured.
+    /// The request runs on its own, so a slow or failing endpoint never holds up the actor.
+    fn notify_connection_webhook(&self, event: ConnectionEvent, room_name: &str) {
+        let connection_webhook_url = match &self.connection_webhook_url {
+            Some(connection_webhook_url) => connection_webhook_url.to_owned(),
+            None => return,
+        };
+
+        let payload = ConnectionWebhookPayload {
+            event,
+            room: room_name.to_owned(),
+            session_count: self
+                .session_ids_by_room_name
+                .get(room_name)
+                .map_or(0, HashSet::len),
+        };
+
+        actix::spawn(async move {
+            match Client::default()
+                .post(&connection_webhook_url)
+                .send_json(&payload)
+                .await
+            {
+                Ok(response) if !response.status().is_success() => log::warn!(
+                    "Connection webhook responded with {} to {:?} event of room '{}'.",
+                    response.status(),
+                    payload.event,
+                    payload.room
+                ),
+                Ok(_) => {}
+                Err(error) => log::warn!(
+                    "Failed to post {:?} event of room '{}' to connection webhook: {}",
+                    payload.event,
+                    payload.room,
+                    error
+                ),
+            }
+        });
+    }
+
+    /// Send message to a single client session.
+    /// Sessions whose mailbox is gone, or full, are dropped rather than sent to again.
+    fn send_message_to_session(&mut self, session_id: usize, message: &str) {
+        if let Some(reply_message_recipient) = self.recipients_by_session_id.get(&session_id) {
+            if let Err(error) = reply_message_recipient.do_send(WebsocketReplyMessage {
+                message: message.to_owned(),
+            }) {
+                log::error!(
+                    "Failed to send message to client with session id '{}', removing it: {}",
+                    session_id,
+                    error
+                );
+                self.remove_session(session_id);
+            }
+        }
+    }
+
+    /// Send the room's join message, if its playlist has one, to a session that just entered it.
+    fn send_join_message(&mut self, room_name: &str, session_id: usize) {
+        let join_message = self
+            .app_state
+            .lock()
+            .unwrap()
+            .song_requests_by_user_id
+            .get(room_name)
+            .and_then(|playlist| playlist.join_message.to_owned());
+
+        if let Some(join_message) = join_message {
+            match serde_json::to_string(&WebsocketEvent::Notice { text: join_message }) {
+                Ok(serialized_event) => self.send_message_to_session(session_id, &serialized_event),
+                Err(error) => log::error!("Failed to serialize join message: {}", error),
+            }
+        }
+    }
+
+    /// Send the room's last broadcasted app state, if any, to a session that just entered it.
+    fn send_last_app_state(&mut self, room_name: &str, session_id: usize) {
+        let last_app_state = self
+            .last_app_state_by_room_name
+            .get(room_name)
+            .map(|last_app_state| last_app_state.for_session(self, session_id).into_owned());
+
+        if let Some(last_app_state) = last_app_state {
+            self.send_message_to_session(session_id, &last_app_state);
+        }
+    }
+
+    /// Like `send_last_app_state`, but skipped when the session already acknowledged that
+    /// version, e.g. when it resumes after a short disconnect without missing a broadcast.
+    fn send_last_app_state_if_stale(&mut self, room_name: &str, session_id: usize) {
+        let acked_version = self.acked_versions_by_session_id.get(&session_id);
+        let is_stale =
+            self.last_app_state_by_room_name
+                .get(room_name)
+                .is_some_and(|last_app_state| {
+                    acked_version
+                        .is_none_or(|acked_version| *acked_version < last_app_state.version)
+                });
+
+        if is_stale {
+            self.send_last_app_state(room_name, session_id);
+        }
+    }
+
+    /// Forget the last app state of a room once its last session left.
+    fn invalidate_last_app_state_if_empty(&mut self, room_name: &str) {
+        let room_is_empty = self
+            .session_ids_by_room_name
+            .get(room_name)
+            .is_none_or(|session_ids| session_ids.is_empty())
+            && !self.sse_senders_by_room_name.contains_key(room_name);
+
+        if room_is_empty {
+            self.last_app_state_by_room_name.remove(room_name);
+        }
+    }
+
+    /// Send a chunk to every server-sent event stream of the room, dropping the ones whose
+    /// client went away.
+    fn send_sse(&mut self, room_name: &str, chunk: Bytes) {
+        if let Some(sse_senders) = self.sse_senders_by_room_name.get_mut(room_name) {
+            sse_senders.retain(|sse_sender| sse_sender.unbounded_send(Ok(chunk.clone())).is_ok());
+
+            if sse_senders.is_empty() {
+                self.sse_senders_by_room_name.remove(room_name);
+                self.invalidate_last_app_state_if_empty(room_name);
+            }
+        }
+    }
+
+    /// Format a message as a server-sent event.
+    fn sse_event(message: &str) -> Bytes {
+        Bytes::from(format!("data: {}\n\n", message))
+    }
+
+    /// Unregister a session and remove it from all rooms.
+    /// Returns whether the session was still registered.
+    fn remove_session(&mut self, session_id: usize) -> bool {
+        let mut rooms: Vec<String> = Vec::new();
+
+        // Remove client session.
+        let was_registered = self.recipients_by_session_id.remove(&session_id).is_some();
+
+        if was_registered {
+            self.kick_recipients_by_session_id.remove(&session_id);
+            self.resume_tokens_by_session_id.remove(&session_id);
+            self.connected_at_by_session_id.remove(&session_id);
+            self.round_trip_time_by_session_id.remove(&session_id);
+            self.acked_versions_by_session_id.remove(&session_id);
+            self.broadcast_interests_by_session_id.remove(&session_id);
+            self.streamer_session_ids.remove(&session_id);
+
+            // Remove session from all rooms.
+            for (room_name, sessions) in &mut self.session_ids_by_room_name {
+                if sessions.remove(&session_id) {
+                    rooms.push(room_name.to_owned());
                 }
             }
         }
+        // // send message to other users
+        // for room in rooms {
+        //     self.send_message(&room, "Someone disconnected", 0);
+        // }
+
+        for room_name in rooms {
+            self.invalidate_last_app_state_if_empty(&room_name);
+            self.notify_connection_webhook(ConnectionEvent::Disconnect, &room_name);
+        }
+
+        was_registered
+    }
+
+    /// Unregister sessions whose actor stopped without sending `DisconnectMessage`, e.g. after a
+    /// panic, and drop room memberships of sessions that aren't registered anymore.
+    /// Backs up the per-session heartbeat, which can't run once the session actor is gone.
+    fn reap_dead_sessions(&mut self) {
+        let dead_session_ids: Vec<usize> = self
+            .recipients_by_session_id
+            .iter()
+            .filter(|(_, reply_message_recipient)| !reply_message_recipient.connected())
+            .map(|(session_id, _)| *session_id)
+            .collect();
+
+        for session_id in dead_session_ids {
+            log::warn!("Reaping dead websocket session '{}'.", session_id);
+            self.remove_session(session_id);
+        }
+
+        let mut pruned_room_names = Vec::new();
+
+        for (room_name, session_ids) in &mut self.session_ids_by_room_name {
+            let recipients_by_session_id = &self.recipients_by_session_id;
+            let session_count = session_ids.len();
+            session_ids.retain(|session_id| recipients_by_session_id.contains_key(session_id));
+
+            if session_ids.len() < session_count {
+                pruned_room_names.push(room_name.to_owned());
+            }
+        }
+
+        for room_name in pruned_room_names {
+            self.invalidate_last_app_state_if_empty(&room_name);
+        }
+    }
+
+    /// Send message to all client sessions in the room.
+    fn send_message(&mut self, room_name: &str, message: &str, skip_session_id: usize) {
+        let session_ids: Vec<usize> = match self.session_ids_by_room_name.get(room_name) {
+            Some(session_ids) => session_ids.iter().copied().collect(),
+            None => return,
+        };
+
+        for session_id in session_ids {
+            if session_id != skip_session_id {
+                self.send_message_to_session(session_id, message);
+            }
+        }
     }
 }
 
 /// New chat session is created
 #[derive(Message)]
-#[rtype(usize)]
+#[rtype(result = "ConnectReply")]
 pub struct ConnectMessage {
     pub room_name: String,
+    /// Token from an earlier connection, to pick its session back up.
+    pub resume_token: Option<String>,
     pub websocket_session_actor_recipient: Recipient<WebsocketReplyMessage>,
+    pub websocket_session_kick_recipient: Recipient<KickSessionMessage>,
+}
+
+pub struct ConnectReply {
+    pub session_id: usize,
+    /// Token the client can reconnect with to resume this session.
+    pub resume_token: String,
+    /// Context of the resumed session, when the connection resumed one.
+    pub resumed_context: Option<SessionContext>,
 }
 
-/// Register new session and assign unique id to this session.
+/// Register new session and assign unique id to this session, or resume the session
+/// matching the resume token when it's still within its grace period.
 impl Handler<ConnectMessage> for WebsocketServerActor {
-    type Result = usize;
+    type Result = MessageResult<ConnectMessage>;
 
     fn handle(&mut self, connect_message: ConnectMessage, _: &mut Context<Self>) -> Self::Result {
         // Notify all users in the same room.
         // self.send_message(&MAIN_ROOM.to_owned(), "Someone joined", 0);
 
-        // Register session with random id.
-        let session_id = self.random_number_generator.gen::<usize>();
+        let resume_grace_period = self.resume_grace_period;
+        let resumable_session = connect_message
+            .resume_token
+            .and_then(|resume_token| self.resumable_sessions_by_token.remove(&resume_token))
+            .filter(|resumable_session| {
+                resumable_session.disconnected_at.elapsed() < resume_grace_period
+            })
+            .filter(|resumable_session| {
+                !self
+                    .recipients_by_session_id
+                    .contains_key(&resumable_session.session_id)
+            });
+
+        // Register session with random id, or the resumed one.
+        let (session_id, resumed_context) = match resumable_session {
+            Some(resumable_session) => (
+                resumable_session.session_id,
+                Some(resumable_session.context),
+            ),
+            None => (self.random_number_generator.gen::<usize>(), None),
+        };
+        let room_name = resumed_context
+            .as_ref()
+            .map_or(connect_message.room_name, |resumed_context| {
+                resumed_context.room_name.to_owned()
+            });
+
         self.recipients_by_session_id.insert(
             session_id,
             connect_message.websocket_session_actor_recipient,
         );
+        self.kick_recipients_by_session_id
+            .insert(session_id, connect_message.websocket_session_kick_recipient);
+
+        self.connected_at_by_session_id
+            .insert(session_id, Instant::now());
+
+        if let Some(round_trip_time) = resumed_context
+            .as_ref()
+            .and_then(|resumed_context| resumed_context.round_trip_time)
+        {
+            self.round_trip_time_by_session_id
+                .insert(session_id, round_trip_time);
+        }
+
+        if let Some(acked_version) = resumed_context
+            .as_ref()
+            .and_then(|resumed_context| resumed_context.acked_version)
+        {
+            self.acked_versions_by_session_id
+                .insert(session_id, acked_version);
+        }
+
+        if let Some(broadcast_interests) = resumed_context
+            .as_ref()
+            .and_then(|resumed_context| resumed_context.broadcast_interests.to_owned())
+        {
+            self.broadcast_interests_by_session_id
+                .insert(session_id, broadcast_interests);
+        }
+
+        let resume_token = Uuid::new_v4().to_string();
+        self.resume_tokens_by_session_id
+            .insert(session_id, resume_token.to_owned());
+
+        if resumed_context
+            .as_ref()
+            .is_some_and(|resumed_context| resumed_context.is_streamer)
+        {
+            self.streamer_session_ids.insert(session_id);
+        }
 
         // Auto join room.
         self.session_ids_by_room_name
-            .entry(connect_message.room_name.to_owned())
-            .or_insert_with(HashSet::new)
+            .entry(room_name.to_owned())
+            .or_default()
             .insert(session_id);
 
-        log::debug!("Client with session id '{}' connected.", session_id);
+        self.send_last_app_state_if_stale(&room_name, session_id);
+        self.send_join_message(&room_name, session_id);
+        self.notify_connection_webhook(ConnectionEvent::Connect, &room_name);
+
+        if resumed_context.is_some() {
+            log::debug!("Client with session id '{}' resumed.", session_id);
+        } else {
+            log::debug!("Client with session id '{}' connected.", session_id);
+        }
+
         // Return client session id back.
-        session_id
+        MessageResult(ConnectReply {
+            session_id,
+            resume_token,
+            resumed_context,
+        })
     }
 }
 
@@ -92,31 +543,32 @@ impl Handler<ConnectMessage> for WebsocketServerActor {
 #[rtype(result = "()")]
 pub struct DisconnectMessage {
     pub websocket_session_id: usize,
+    /// Session state to restore if the client resumes the session.
+    pub session_context: SessionContext,
 }
 
 impl Handler<DisconnectMessage> for WebsocketServerActor {
     type Result = ();
 
     fn handle(&mut self, disconnect_message: DisconnectMessage, _: &mut Context<Self>) {
-        let mut rooms: Vec<String> = Vec::new();
+        let resume_token = self
+            .resume_tokens_by_session_id
+            .get(&disconnect_message.websocket_session_id)
+            .cloned();
 
-        // Remove client session.
-        if self
-            .recipients_by_session_id
-            .remove(&disconnect_message.websocket_session_id)
-            .is_some()
-        {
-            // Remove session from all rooms.
-            for (room_name, sessions) in &mut self.session_ids_by_room_name {
-                if sessions.remove(&disconnect_message.websocket_session_id) {
-                    rooms.push(room_name.to_owned());
-                }
+        // Kicked sessions are already unregistered, so they can't be resumed.
+        if self.remove_session(disconnect_message.websocket_session_id) {
+            if let Some(resume_token) = resume_token {
+                self.resumable_sessions_by_token.insert(
+                    resume_token,
+                    ResumableSession {
+                        session_id: disconnect_message.websocket_session_id,
+                        context: disconnect_message.session_context,
+                        disconnected_at: Instant::now(),
+                    },
+                );
             }
         }
-        // // send message to other users
-        // for room in rooms {
-        //     self.send_message(&room, "Someone disconnected", 0);
-        // }
 
         log::debug!(
             "Client with session id '{}' disconnected.",
@@ -125,6 +577,54 @@ impl Handler<DisconnectMessage> for WebsocketServerActor {
     }
 }
 
+/// Forcibly disconnect a session from a room, on behalf of the room's streamer.
+#[derive(Message)]
+#[rtype(result = "bool")]
+pub struct KickMessage {
+    /// Room the kicking streamer owns. Sessions in other rooms can't be kicked.
+    pub room_name: String,
+    /// Session to disconnect.
+    pub session_id: usize,
+}
+
+/// Returns whether the session was found in the room and kicked.
+impl Handler<KickMessage> for WebsocketServerActor {
+    type Result = bool;
+
+    fn handle(&mut self, kick_message: KickMessage, _: &mut Context<Self>) -> Self::Result {
+        let is_in_room = self
+            .session_ids_by_room_name
+            .get(&kick_message.room_name)
+            .is_some_and(|session_ids| session_ids.contains(&kick_message.session_id));
+
+        if !is_in_room {
+            return false;
+        }
+
+        if let Some(kick_recipient) = self
+            .kick_recipients_by_session_id
+            .get(&kick_message.session_id)
+        {
+            if let Err(error) = kick_recipient.do_send(KickSessionMessage) {
+                log::error!(
+                    "Failed to kick client with session id '{}': {}",
+                    kick_message.session_id,
+                    error
+                );
+            }
+        }
+
+        self.remove_session(kick_message.session_id);
+
+        log::info!(
+            "Client with session id '{}' was kicked from room '{}'.",
+            kick_message.session_id,
+            kick_message.room_name
+        );
+        true
+    }
+}
+
 #[derive(Message)]
 #[rtype(result = "()")]
 pub struct ClientMessage {
@@ -161,14 +661,418 @@ impl Handler<ListRoomsMessage> for WebsocketServerActor {
     fn handle(&mut self, _: ListRoomsMessage, _: &mut Context<Self>) -> Self::Result {
         let mut room_names = Vec::new();
 
-        for room_name in self.session_ids_by_room_name.keys() {
-            room_names.push(room_name.to_owned())
+        // Rooms everyone left stay registered, but aren't active anymore.
+        for (room_name, session_ids) in &self.session_ids_by_room_name {
+            if !session_ids.is_empty() {
+                room_names.push(room_name.to_owned())
+            }
         }
 
         MessageResult(room_names)
     }
 }
 
+/// Check whether another websocket session fits under the connection caps. Replies with how
+/// long the client should back off when it doesn't.
+pub struct CheckConnectionCapacityMessage {
+    pub room_name: String,
+    pub max_connections_per_room: Option<usize>,
+    pub max_connections: Option<usize>,
+}
+
+impl actix::Message for CheckConnectionCapacityMessage {
+    type Result = Option<Duration>;
+}
+
+impl Handler<CheckConnectionCapacityMessage> for WebsocketServerActor {
+    type Result = Option<Duration>;
+
+    fn handle(
+        &mut self,
+        check_connection_capacity_message: CheckConnectionCapacityMessage,
+        _: &mut Context<Self>,
+    ) -> Option<Duration> {
+        let now = Instant::now();
+
+        while let Some(rejected_at) = self.rejected_connection_instants.front() {
+            if now.duration_since(*rejected_at) < CONNECTION_REJECTION_WINDOW {
+                break;
+            }
+            self.rejected_connection_instants.pop_front();
+        }
+
+        let room_connection_count = self
+            .session_ids_by_room_name
+            .get(&check_connection_capacity_message.room_name)
+            .map_or(0, HashSet::len);
+        let total_connection_count = self.recipients_by_session_id.len();
+        let recent_rejection_count = self.rejected_connection_instants.len();
+
+        let retry_after = [
+            (
+                room_connection_count,
+                check_connection_capacity_message.max_connections_per_room,
+            ),
+            (
+                total_connection_count,
+                check_connection_capacity_message.max_connections,
+            ),
+        ]
+        .iter()
+        .filter_map(|&(connection_count, max_connections)| {
+            let max_connections =
+                max_connections.filter(|&max_connections| connection_count >= max_connections)?;
+            // Clients still retrying count towards the load, so the suggested backoff grows
+            // while they keep hammering.
+            let load =
+                (connection_count + recent_rejection_count) as f64 / max_connections.max(1) as f64;
+            Some(
+                CONNECTION_RETRY_BASE
+                    .mul_f64(load.max(1.0))
+                    .min(MAX_CONNECTION_RETRY),
+            )
+        })
+        .max();
+
+        if retry_after.is_some() {
+            self.rejected_connection_instants.push_back(now);
+        }
+
+        retry_after
+    }
+}
+
+/// List every connected session, for moderation.
+pub struct ListSessionsMessage;
+
+impl actix::Message for ListSessionsMessage {
+    type Result = Vec<SessionStats>;
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SessionStats {
+    session_id: usize,
+    room_names: Vec<String>,
+    /// How long the session has been connected, in whole seconds.
+    uptime_secs: u64,
+    /// Latest heartbeat round-trip time, in milliseconds, once a ping has been answered.
+    round_trip_time_ms: Option<f64>,
+    /// Latest app state version the session acknowledged, to spot clients that fell behind.
+    acked_version: Option<u64>,
+}
+
+/// Handler for `ListSessionsMessage` message.
+impl Handler<ListSessionsMessage> for WebsocketServerActor {
+    type Result = MessageResult<ListSessionsMessage>;
+
+    fn handle(&mut self, _: ListSessionsMessage, _: &mut Context<Self>) -> Self::Result {
+        let mut session_stats = Vec::new();
+
+        for (session_id, connected_at) in &self.connected_at_by_session_id {
+            let room_names = self
+                .session_ids_by_room_name
+                .iter()
+                .filter(|(_, session_ids)| session_ids.contains(session_id))
+                .map(|(room_name, _)| room_name.to_owned())
+                .collect();
+
+            session_stats.push(SessionStats {
+                session_id: *session_id,
+                room_names,
+                uptime_secs: connected_at.elapsed().as_secs(),
+                round_trip_time_ms: self
+                    .round_trip_time_by_session_id
+                    .get(session_id)
+                    .map(|round_trip_time| round_trip_time.as_secs_f64() * 1000.0),
+                acked_version: self.acked_versions_by_session_id.get(session_id).copied(),
+            });
+        }
+
+        MessageResult(session_stats)
+    }
+}
+
+/// Register a read-only client following a room over server-sent events.
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct ConnectSseMessage {
+    pub room_name: String,
+    pub sse_sender: SseSender,
+}
+
+impl Handler<ConnectSseMessage> for WebsocketServerActor {
+    type Result = ();
+
+    fn handle(&mut self, connect_sse_message: ConnectSseMessage, _: &mut Context<Self>) {
+        let ConnectSseMessage {
+            room_name,
+            sse_sender,
+        } = connect_sse_message;
+
+        if let Some(last_app_state) = self.last_app_state_by_room_name.get(&room_name) {
+            let _ = sse_sender
+                .unbounded_send(Ok(WebsocketServerActor::sse_event(&last_app_state.public)));
+        }
+
+        log::debug!(
+            "Server-sent events client connected to room '{}'.",
+            room_name
+        );
+        self.sse_senders_by_room_name
+            .entry(room_name)
+            .or_default()
+            .push(sse_sender);
+    }
+}
+
+/// Wait for the next change of a room. The returned receiver completes when it's broadcasted.
+#[derive(Message)]
+#[rtype(result = "oneshot::Receiver<()>")]
+pub struct WaitForChangeMessage {
+    pub room_name: String,
+}
+
+impl Handler<WaitForChangeMessage> for WebsocketServerActor {
+    type Result = MessageResult<WaitForChangeMessage>;
+
+    fn handle(
+        &mut self,
+        wait_for_change_message: WaitForChangeMessage,
+        _: &mut Context<Self>,
+    ) -> Self::Result {
+        let (poll_waiter, change_receiver) = oneshot::channel();
+
+        let poll_waiters = self
+            .poll_waiters_by_room_name
+            .entry(wait_for_change_message.room_name)
+            .or_default();
+        // Drop the waiters of polls that already timed out.
+        poll_waiters.retain(|poll_waiter| !poll_waiter.is_canceled());
+        poll_waiters.push(poll_waiter);
+
+        MessageResult(change_receiver)
+    }
+}
+
+/// Latest heartbeat round-trip time measured by a session.
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct RoundTripTimeMessage {
+    pub session_id: usize,
+    pub round_trip_time: Duration,
+}
+
+impl Handler<RoundTripTimeMessage> for WebsocketServerActor {
+    type Result = ();
+
+    fn handle(&mut self, round_trip_time_message: RoundTripTimeMessage, _: &mut Context<Self>) {
+        // Ignore measurements racing with the session's disconnection.
+        if self
+            .recipients_by_session_id
+            .contains_key(&round_trip_time_message.session_id)
+        {
+            self.round_trip_time_by_session_id.insert(
+                round_trip_time_message.session_id,
+                round_trip_time_message.round_trip_time,
+            );
+        }
+    }
+}
+
+/// Record the latest app state version a session acknowledged having processed.
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct AckVersionMessage {
+    pub session_id: usize,
+    pub version: u64,
+}
+
+impl Handler<AckVersionMessage> for WebsocketServerActor {
+    type Result = ();
+
+    fn handle(&mut self, ack_version_message: AckVersionMessage, _: &mut Context<Self>) {
+        // Ignore acknowledgements racing with the session's disconnection.
+        if self
+            .recipients_by_session_id
+            .contains_key(&ack_version_message.session_id)
+        {
+            self.acked_versions_by_session_id
+                .insert(ack_version_message.session_id, ack_version_message.version);
+        }
+    }
+}
+
+/// Part of the app state websocket clients can subscribe to with `/subscribe`, so minimal
+/// clients like a now playing overlay don't get sent the whole queue on every change.
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub enum BroadcastInterest {
+    /// The request being played.
+    Current,
+    /// Queued requests and the ones awaiting approval.
+    Queue,
+    /// Recently played requests.
+    History,
+    /// Whether requests are open or paused, the arrangements and the auto-close countdown.
+    Stats,
+}
+
+impl BroadcastInterest {
+    pub fn from_name(name: &str) -> Option<BroadcastInterest> {
+        match name {
+            "current" => Some(BroadcastInterest::Current),
+            "queue" => Some(BroadcastInterest::Queue),
+            "history" => Some(BroadcastInterest::History),
+            "stats" => Some(BroadcastInterest::Stats),
+            _ => None,
+        }
+    }
+
+    /// `AppStateResponse` fields sent for the interest.
+    fn fields(self) -> &'static [&'static str] {
+        match self {
+            BroadcastInterest::Current => &["currentRequest"],
+            BroadcastInterest::Queue => &["songRequests", "pendingApproval"],
+            BroadcastInterest::History => &["history"],
+            BroadcastInterest::Stats => &[
+                "songRequestsEnabled",
+                "songArrangements",
+                "paused",
+                "autoCloseAfter",
+            ],
+        }
+    }
+}
+
+/// Set the parts of the app state a session gets broadcast, or `None` for all of them.
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct SubscribeMessage {
+    pub session_id: usize,
+    pub broadcast_interests: Option<HashSet<BroadcastInterest>>,
+}
+
+impl Handler<SubscribeMessage> for WebsocketServerActor {
+    type Result = ();
+
+    fn handle(&mut self, subscribe_message: SubscribeMessage, _: &mut Context<Self>) {
+        let SubscribeMessage {
+            session_id,
+            broadcast_interests,
+        } = subscribe_message;
+
+        // Ignore subscriptions racing with the session's disconnection.
+        if !self.recipients_by_session_id.contains_key(&session_id) {
+            return;
+        }
+
+        match broadcast_interests {
+            Some(broadcast_interests) => {
+                self.broadcast_interests_by_session_id
+                    .insert(session_id, broadcast_interests);
+            }
+            None => {
+                self.broadcast_interests_by_session_id.remove(&session_id);
+            }
+        }
+    }
+}
+
+/// Fetch the recently played songs of a room.
+pub struct HistoryMessage {
+    pub room_name: String,
+    /// Whether the asking session is the room's streamer, who gets the history in full rather
+    /// than its public view.
+    pub is_streamer: bool,
+}
+
+impl actix::Message for HistoryMessage {
+    type Result = Vec<SongRequest>;
+}
+
+/// Handler for `HistoryMessage` message.
+impl Handler<HistoryMessage> for WebsocketServerActor {
+    type Result = MessageResult<HistoryMessage>;
+
+    fn handle(&mut self, history_message: HistoryMessage, _: &mut Context<Self>) -> Self::Result {
+        let app_state = self.app_state.lock().unwrap();
+
+        let history = app_state
+            .song_requests_by_user_id
+            .get(&history_message.room_name)
+            .map_or_else(Vec::new, |playlist| {
+                if history_message.is_streamer {
+                    playlist.history.to_owned()
+                } else {
+                    playlist
+                        .history
+                        .iter()
+                        .cloned()
+                        .map(|song_request| playlist.public_song_request(song_request))
+                        .collect()
+                }
+            });
+
+        MessageResult(history)
+    }
+}
+
+/// Count the websocket sessions in a room.
+pub struct RoomStatsMessage {
+    pub room_name: String,
+}
+
+impl actix::Message for RoomStatsMessage {
+    type Result = usize;
+}
+
+/// Handler for `RoomStatsMessage` message.
+impl Handler<RoomStatsMessage> for WebsocketServerActor {
+    type Result = MessageResult<RoomStatsMessage>;
+
+    fn handle(
+        &mut self,
+        room_stats_message: RoomStatsMessage,
+        _: &mut Context<Self>,
+    ) -> Self::Result {
+        let viewers = self
+            .session_ids_by_room_name
+            .get(&room_stats_message.room_name)
+            .map_or(0, |session_ids| session_ids.len());
+
+        MessageResult(viewers)
+    }
+}
+
+/// Mark a session as authenticated as the streamer of its room, and send it the full app
+/// state right away.
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct StreamerAuthenticatedMessage {
+    pub session_id: usize,
+    pub room_name: String,
+}
+
+impl Handler<StreamerAuthenticatedMessage> for WebsocketServerActor {
+    type Result = ();
+
+    fn handle(
+        &mut self,
+        streamer_authenticated_message: StreamerAuthenticatedMessage,
+        _: &mut Context<Self>,
+    ) {
+        let StreamerAuthenticatedMessage {
+            session_id,
+            room_name,
+        } = streamer_authenticated_message;
+
+        if self.recipients_by_session_id.contains_key(&session_id) {
+            self.streamer_session_ids.insert(session_id);
+            self.send_last_app_state(&room_name, session_id);
+        }
+    }
+}
+
 /// Join room, if room does not exists create new one.
 #[derive(Message)]
 #[rtype(result = "()")]
@@ -191,6 +1095,10 @@ impl Handler<JoinMessage> for WebsocketServerActor {
         } = join_message;
         let mut room_names = Vec::new();
 
+        // Streamer rights and acknowledged versions are per room.
+        self.streamer_session_ids.remove(&session_id);
+        self.acked_versions_by_session_id.remove(&session_id);
+
         // Remove session from all rooms.
         for (room_name, session_ids) in &mut self.session_ids_by_room_name {
             if session_ids.remove(&session_id) {
@@ -201,14 +1109,170 @@ impl Handler<JoinMessage> for WebsocketServerActor {
         // Send message to other users.
         for room_name in room_names {
             self.send_message(&room_name, "Someone disconnected", 0);
+            self.invalidate_last_app_state_if_empty(&room_name);
+            self.notify_connection_webhook(ConnectionEvent::Disconnect, &room_name);
         }
 
         self.session_ids_by_room_name
             .entry(room_name.clone())
-            .or_insert_with(HashSet::new)
+            .or_default()
             .insert(session_id);
 
         self.send_message(&room_name, "Someone connected", session_id);
+        self.send_last_app_state(&room_name, session_id);
+        self.send_join_message(&room_name, session_id);
+        self.notify_connection_webhook(ConnectionEvent::Connect, &room_name);
+    }
+}
+
+/// Session lifecycle events posted to the connection webhook.
+#[derive(Serialize, Debug, Clone, Copy)]
+#[serde(rename_all = "camelCase")]
+enum ConnectionEvent {
+    Connect,
+    Disconnect,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct ConnectionWebhookPayload {
+    event: ConnectionEvent,
+    room: String,
+    /// Sessions in the room after the event.
+    session_count: usize,
+}
+
+/// Typed events pushed to websocket clients, serialized as `{"type": ..., ...}`.
+#[derive(Serialize)]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub enum WebsocketEvent {
+    /// Informational text for the client to display.
+    Notice { text: String },
+    /// The streamer opened or closed song requests.
+    RequestsToggled { enabled: bool },
+    /// The streamer paused or resumed advancing through the queue.
+    Paused { paused: bool },
+    /// A request was deleted from `position` in the queue, sent before the updated app state
+    /// so clients can animate or reconcile that specific removal.
+    #[serde(rename_all = "camelCase")]
+    Removed {
+        request_id: String,
+        song_id: String,
+        position: usize,
+    },
+    /// A song request was turned down, for playlists with `broadcast_rejections` on.
+    #[serde(rename_all = "camelCase")]
+    Rejected {
+        viewer_id: String,
+        song_id: String,
+        reason: RejectionReason,
+    },
+}
+
+/// Why a song request was turned down.
+#[derive(Serialize, Clone, Copy)]
+#[serde(rename_all = "camelCase")]
+pub enum RejectionReason {
+    /// Song requests are closed.
+    Closed,
+    /// The viewer, or whoever gifted the request, is banned.
+    Banned,
+    /// Someone already requested the song and the playlist rejects duplicates.
+    Duplicate,
+    /// The song is still cooling down since it was last played or removed.
+    Cooldown,
+}
+
+/// Broadcast a typed event to every session in a user's room.
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct BroadcastEventMessage {
+    pub user_id: String,
+    pub event: WebsocketEvent,
+}
+
+/// Broadcast a typed event to every session, in every room.
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct BroadcastEventToAllRoomsMessage {
+    pub event: WebsocketEvent,
+}
+
+impl Handler<BroadcastEventToAllRoomsMessage> for WebsocketServerActor {
+    type Result = ();
+
+    fn handle(
+        &mut self,
+        broadcast_event_to_all_rooms_message: BroadcastEventToAllRoomsMessage,
+        _: &mut Context<Self>,
+    ) {
+        match serde_json::to_string(&broadcast_event_to_all_rooms_message.event) {
+            Ok(serialized_event) => {
+                log::debug!("Broadcasted event to all rooms: {:?}", serialized_event);
+                let session_ids: Vec<usize> =
+                    self.recipients_by_session_id.keys().copied().collect();
+
+                for session_id in session_ids {
+                    self.send_message_to_session(session_id, &serialized_event);
+                }
+            }
+            Err(error) => log::error!("Failed to serialize websocket event: {}", error),
+        }
+    }
+}
+
+impl Handler<BroadcastEventMessage> for WebsocketServerActor {
+    type Result = ();
+
+    fn handle(&mut self, broadcast_event_message: BroadcastEventMessage, _: &mut Context<Self>) {
+        match serde_json::to_string(&broadcast_event_message.event) {
+            Ok(serialized_event) => {
+                log::debug!("Broadcasted event: {:?}", serialized_event);
+                self.send_message(&broadcast_event_message.user_id, &serialized_event, 0);
+            }
+            Err(error) => log::error!("Failed to serialize websocket event: {}", error),
+        }
+    }
+}
+
+/// Tell a room its song requests opened or closed on schedule.
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct ScheduledToggleMessage {
+    pub user_id: String,
+    pub enabled: bool,
+}
+
+impl Handler<ScheduledToggleMessage> for WebsocketServerActor {
+    type Result = ();
+
+    fn handle(
+        &mut self,
+        scheduled_toggle_message: ScheduledToggleMessage,
+        context: &mut Context<Self>,
+    ) {
+        let ScheduledToggleMessage { user_id, enabled } = scheduled_toggle_message;
+
+        log::info!(
+            "Song requests for user '{}' {} on schedule.",
+            user_id,
+            if enabled { "opened" } else { "closed" }
+        );
+
+        context.notify(BroadcastEventMessage {
+            user_id: user_id.to_owned(),
+            event: WebsocketEvent::RequestsToggled { enabled },
+        });
+        context.notify(BroadcastEventMessage {
+            user_id,
+            event: WebsocketEvent::Notice {
+                text: if enabled {
+                    "Song requests are now open.".to_owned()
+                } else {
+                    "Song requests are now closed.".to_owned()
+                },
+            },
+        });
     }
 }
 
@@ -223,7 +1287,155 @@ pub struct BroadcastAppStateMessage {
 struct AppStateResponse {
     song_requests_enabled: bool,
     song_arrangements: Vec<ArrangementType>,
-    song_requests: Vec<SongRequest>,
+    song_requests: Vec<QueuedSongRequest>,
+    current_request: Option<SongRequest>,
+    history: Vec<SongRequest>,
+    paused: bool,
+    version: u64,
+    /// How many more requests will be played before requests close, if a countdown is set.
+    auto_close_remaining: Option<usize>,
+    pending_approval: Vec<QueuedSongRequest>,
+}
+
+/// Request that hasn't been played yet, with what overlays show about it: how long it's been
+/// waiting as of the broadcast, to highlight viewers who've been patient, and how many viewers
+/// asked for it.
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct QueuedSongRequest {
+    #[serde(flatten)]
+    song_request: SongRequest,
+    /// Seconds since the request was made. Left out for requests without `requested_at`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    waiting_secs: Option<u64>,
+    /// Viewers behind the request, counting the ones merged into it as duplicates.
+    requester_count: usize,
+}
+
+impl QueuedSongRequest {
+    fn list(song_requests: &[SongRequest], now: Timestamp) -> Vec<QueuedSongRequest> {
+        song_requests
+            .iter()
+            .map(|song_request| QueuedSongRequest {
+                song_request: song_request.to_owned(),
+                waiting_secs: song_request
+                    .requested_at
+                    .map(|requested_at| now.secs_since(requested_at)),
+                requester_count: song_request.requester_count(),
+            })
+            .collect()
+    }
+}
+
+impl From<&Playlist> for AppStateResponse {
+    fn from(playlist: &Playlist) -> Self {
+        let now = Timestamp::now();
+
+        AppStateResponse {
+            song_requests_enabled: playlist.song_requests_enabled,
+            song_arrangements: playlist.song_arrangements.to_owned(),
+            song_requests: QueuedSongRequest::list(&playlist.song_requests, now),
+            current_request: playlist.current_request.to_owned(),
+            history: playlist.history.to_owned(),
+            paused: playlist.paused,
+            version: playlist.version,
+            auto_close_remaining: playlist.auto_close_remaining,
+            pending_approval: QueuedSongRequest::list(&playlist.pending_approval, now),
+        }
+    }
+}
+
+/// Serialize a room's app state for a broadcast, or log why it couldn't be and return `None`.
+/// Panicking would stop the actor and drop every session, so the change is skipped instead.
+/// Sessions catch up with the next broadcast that serializes.
+fn serialize_for_broadcast<T>(
+    room_name: &str,
+    serialize: impl FnOnce() -> serde_json::Result<T>,
+) -> Option<T> {
+    match serialize() {
+        Ok(serialized) => Some(serialized),
+        Err(error) => {
+            log::error!(
+                "Failed to serialize app state of room '{}', skipping broadcast: {}",
+                room_name,
+                error
+            );
+            None
+        }
+    }
+}
+
+/// Serialized `AppStateResponse` of a room, as seen by its streamer and by everyone else.
+struct SerializedAppState {
+    version: u64,
+    public: String,
+    streamer: String,
+}
+
+impl SerializedAppState {
+    fn new(playlist: &Playlist) -> serde_json::Result<SerializedAppState> {
+        Ok(SerializedAppState {
+            version: playlist.version,
+            public: serde_json::to_string(&AppStateResponse::from(&playlist.public_view()))?,
+            streamer: serde_json::to_string(&AppStateResponse::from(playlist))?,
+        })
+    }
+
+    fn for_session(
+        &self,
+        websocket_server_actor: &WebsocketServerActor,
+        session_id: usize,
+    ) -> Cow<'_, str> {
+        let serialized_app_state = if websocket_server_actor
+            .streamer_session_ids
+            .contains(&session_id)
+        {
+            &self.streamer
+        } else {
+            &self.public
+        };
+
+        let broadcast_interests = match websocket_server_actor
+            .broadcast_interests_by_session_id
+            .get(&session_id)
+        {
+            Some(broadcast_interests) => broadcast_interests,
+            None => return Cow::Borrowed(serialized_app_state),
+        };
+
+        match SerializedAppState::only(serialized_app_state, broadcast_interests) {
+            Ok(shaped_app_state) => Cow::Owned(shaped_app_state),
+            Err(error) => {
+                log::error!(
+                    "Failed to shape app state for session {}, sending all of it: {}",
+                    session_id,
+                    error
+                );
+                Cow::Borrowed(serialized_app_state)
+            }
+        }
+    }
+
+    /// The serialized app state with only the fields of `broadcast_interests`, and its version.
+    fn only(
+        serialized_app_state: &str,
+        broadcast_interests: &HashSet<BroadcastInterest>,
+    ) -> serde_json::Result<String> {
+        let fields: serde_json::Map<String, serde_json::Value> =
+            serde_json::from_str(serialized_app_state)?;
+
+        let shaped_fields: serde_json::Map<String, serde_json::Value> = fields
+            .into_iter()
+            .filter(|(field, _)| {
+                field == "version"
+                    || broadcast_interests.iter().any(|broadcast_interest| {
+                        broadcast_interest.fields().contains(&field.as_str())
+                    })
+            })
+            .collect();
+
+        serde_json::to_string(&shaped_fields)
+    }
 }
 
 impl Handler<BroadcastAppStateMessage> for WebsocketServerActor {
@@ -232,39 +1444,202 @@ impl Handler<BroadcastAppStateMessage> for WebsocketServerActor {
     fn handle(
         &mut self,
         broadcast_app_state_message: BroadcastAppStateMessage,
-        _: &mut Context<Self>,
+        context: &mut Context<Self>,
     ) {
-        let app_state = self.app_state.lock().unwrap();
+        let room_name = broadcast_app_state_message.user_id;
 
-        let default_playlist = Playlist {
-            song_requests_enabled: false,
-            song_arrangements: vec![
-                ArrangementType::Lead,
-                ArrangementType::Rhythm,
-                ArrangementType::Bass,
-                ArrangementType::Drums,
-                ArrangementType::Vocals,
-            ],
-            song_requests: vec![],
-        };
+        // Every playlist change is broadcast, so this is where the state gets marked for saving.
+        if let Some(persistence) = &self.persistence {
+            persistence.mark_dirty();
+        }
+
+        if let Some(playlist) = self
+            .app_state
+            .lock()
+            .unwrap()
+            .song_requests_by_user_id
+            .get_mut(&room_name)
+        {
+            playlist.version += 1;
+        }
+
+        // Within the room's broadcast interval, the change goes out with the state at its end.
+        let remaining_interval = self.broadcast_interval.and_then(|broadcast_interval| {
+            self.last_broadcast_instants_by_room_name
+                .get(&room_name)
+                .and_then(|last_broadcast_instant| {
+                    broadcast_interval.checked_sub(last_broadcast_instant.elapsed())
+                })
+        });
+
+        match remaining_interval {
+            Some(remaining_interval) => {
+                if self
+                    .deferred_broadcast_room_names
+                    .insert(room_name.to_owned())
+                {
+                    context.run_later(remaining_interval, move |websocket_server_actor, _| {
+                        websocket_server_actor
+                            .deferred_broadcast_room_names
+                            .remove(&room_name);
+                        websocket_server_actor.broadcast_app_state(room_name);
+                    });
+                }
+            }
+            None => self.broadcast_app_state(room_name),
+        }
+    }
+}
+
+impl WebsocketServerActor {
+    /// Send the room's current app state to its websocket sessions and server-sent event
+    /// streams, and answer its long polls.
+    fn broadcast_app_state(&mut self, room_name: String) {
+        let app_state = self.app_state.lock().unwrap();
+        let default_playlist = app_state.new_playlist();
 
         let playlist = app_state
             .song_requests_by_user_id
-            .get(&broadcast_app_state_message.user_id)
+            .get(&room_name)
             .unwrap_or(&default_playlist);
 
-        let serialized_app_state_response = serde_json::to_string(&AppStateResponse {
-            song_requests_enabled: playlist.song_requests_enabled,
-            song_arrangements: playlist.song_arrangements.to_owned(),
-            song_requests: playlist.song_requests.to_owned(),
-        })
-        .unwrap();
+        let serialized_app_state =
+            match serialize_for_broadcast(&room_name, || SerializedAppState::new(playlist)) {
+                Some(serialized_app_state) => serialized_app_state,
+                None => return,
+            };
+        drop(app_state);
 
-        log::debug!("Broadcasted app state: {:?}", serialized_app_state_response);
-        self.send_message(
-            &broadcast_app_state_message.user_id,
-            serialized_app_state_response.as_str(),
-            0,
+        log::debug!("Broadcasted app state: {:?}", serialized_app_state.streamer);
+
+        let session_ids: Vec<usize> = self
+            .session_ids_by_room_name
+            .get(&room_name)
+            .map_or_else(Vec::new, |session_ids| {
+                session_ids.iter().copied().collect()
+            });
+
+        for session_id in session_ids {
+            let app_state = serialized_app_state.for_session(self, session_id);
+            self.send_message_to_session(session_id, &app_state);
+        }
+
+        self.send_sse(
+            &room_name,
+            WebsocketServerActor::sse_event(&serialized_app_state.public),
+        );
+
+        if let Some(poll_waiters) = self.poll_waiters_by_room_name.remove(&room_name) {
+            for poll_waiter in poll_waiters {
+                let _ = poll_waiter.send(());
+            }
+        }
+
+        if let Some(broadcast_interval) = self.broadcast_interval {
+            let now = Instant::now();
+            self.last_broadcast_instants_by_room_name
+                .retain(|_, last_broadcast_instant| {
+                    now.duration_since(*last_broadcast_instant) < broadcast_interval
+                });
+            self.last_broadcast_instants_by_room_name
+                .insert(room_name.to_owned(), now);
+        }
+
+        self.last_app_state_by_room_name
+            .insert(room_name, serialized_app_state);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::TestContext;
+
+    /// Stand-in for a session actor, which can die without disconnecting.
+    struct StubSession {
+        alive: bool,
+    }
+
+    impl Actor for StubSession {
+        type Context = Context<Self>;
+
+        fn started(&mut self, context: &mut Context<Self>) {
+            if !self.alive {
+                context.stop();
+            }
+        }
+    }
+
+    impl Handler<WebsocketReplyMessage> for StubSession {
+        type Result = ();
+
+        fn handle(&mut self, _: WebsocketReplyMessage, _: &mut Context<Self>) {}
+    }
+
+    struct Unserializable;
+
+    impl Serialize for Unserializable {
+        fn serialize<S: serde::Serializer>(&self, _: S) -> Result<S::Ok, S::Error> {
+            Err(serde::ser::Error::custom("not today"))
+        }
+    }
+
+    #[test]
+    fn broadcasts_that_fail_to_serialize_are_skipped() {
+        assert_eq!(
+            serialize_for_broadcast("streamer", || serde_json::to_string(&Unserializable)),
+            None
+        );
+        assert_eq!(
+            serialize_for_broadcast("streamer", || serde_json::to_string(&[1, 2])),
+            Some("[1,2]".to_owned())
+        );
+    }
+
+    #[actix_rt::test]
+    async fn dead_sessions_are_reaped_from_every_room() {
+        let context = TestContext::default();
+        let mut websocket_server_actor = WebsocketServerActor::new(
+            context.app_state.clone(),
+            None,
+            Duration::from_secs(30),
+            None,
+            None,
+        );
+        let dead_session = StubSession { alive: false }.start().recipient();
+        while dead_session.connected() {
+            actix_rt::time::delay_for(Duration::from_millis(10)).await;
+        }
+        websocket_server_actor
+            .recipients_by_session_id
+            .insert(1, StubSession { alive: true }.start().recipient());
+        websocket_server_actor
+            .recipients_by_session_id
+            .insert(2, dead_session);
+        // Session 3 was never registered, or was removed without leaving its room.
+        websocket_server_actor
+            .session_ids_by_room_name
+            .insert("streamer".to_owned(), [1, 2, 3].iter().copied().collect());
+        websocket_server_actor
+            .session_ids_by_room_name
+            .insert("elsewhere".to_owned(), [2].iter().copied().collect());
+
+        websocket_server_actor.reap_dead_sessions();
+
+        assert_eq!(
+            websocket_server_actor
+                .recipients_by_session_id
+                .keys()
+                .collect::<Vec<_>>(),
+            [&1]
+        );
+        assert_eq!(
+            websocket_server_actor.session_ids_by_room_name["streamer"],
+            [1].iter().copied().collect()
         );
+        assert!(websocket_server_actor
+            .session_ids_by_room_name
+            .get("elsewhere")
+            .is_none_or(HashSet::is_empty));
     }
 }
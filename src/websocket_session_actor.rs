@@ -1,7 +1,13 @@
+use std::collections::HashSet;
 use std::time::{Duration, Instant};
 
 use actix::*;
+use actix_http::ws::Item;
+use actix_web::web;
 use actix_web_actors::ws;
+use serde::Serialize;
+use crate::config::Config;
+use crate::throughput_limiter::ThroughputLimiter;
 use crate::websocket_server_actor;
 
 pub struct WebsocketSessionActor {
@@ -10,28 +16,100 @@ pub struct WebsocketSessionActor {
     /// Client must send ping at least once per 10 seconds (CLIENT_TIMEOUT),
     /// otherwise we drop the connection.
     pub last_heartbeat: Instant,
+    /// When the client session connected.
+    pub connected_at: Instant,
     /// Room the client session is in.
     pub room_name: String,
     /// Websocket server actor address.
     pub websocket_server_actor_address: Addr<websocket_server_actor::WebsocketServerActor>,
+    /// Text fragments received so far for a message split across continuation frames.
+    pub continuation_buffer: Option<Vec<u8>>,
+    /// Sequence number carried by the last heartbeat ping, and when it was sent.
+    pub last_ping: Option<(u64, Instant)>,
+    /// Round-trip time measured from the last answered heartbeat ping.
+    pub round_trip_time: Option<Duration>,
+    /// Whether the client authenticated as the streamer owning `room_name`.
+    pub is_streamer: bool,
+    /// Server configuration, holding the streamer tokens `/auth` checks against.
+    pub config: web::Data<Config>,
+    /// Token the client connected with to resume an earlier session.
+    pub resume_token: Option<String>,
+    /// Latest app state version the client acknowledged with `/ack`.
+    pub acked_version: Option<u64>,
+    /// Parts of the app state the client subscribed to with `/subscribe`, or `None` for all.
+    pub broadcast_interests: Option<HashSet<websocket_server_actor::BroadcastInterest>>,
+    /// Caps how many text messages the client sends per second, when configured.
+    pub message_rate_limiter: ThroughputLimiter,
+}
+
+impl WebsocketSessionActor {
+    pub fn new(
+        room_name: String,
+        websocket_server_actor_address: Addr<websocket_server_actor::WebsocketServerActor>,
+        config: web::Data<Config>,
+        resume_token: Option<String>,
+    ) -> WebsocketSessionActor {
+        let message_rate_limiter = ThroughputLimiter::new(config.websocket_message_rate_limit_per_sec);
+
+        WebsocketSessionActor {
+            session_id: 0,
+            last_heartbeat: Instant::now(),
+            connected_at: Instant::now(),
+            room_name,
+            websocket_server_actor_address,
+            continuation_buffer: None,
+            last_ping: None,
+            round_trip_time: None,
+            is_streamer: false,
+            config,
+            resume_token,
+            acked_version: None,
+            broadcast_interests: None,
+            message_rate_limiter,
+        }
+    }
 }
 
 impl Actor for WebsocketSessionActor {
     type Context = ws::WebsocketContext<Self>;
 
     fn started(&mut self, websocket_context: &mut Self::Context) {
+        self.connected_at = Instant::now();
         self.check_heartbeat(websocket_context);
         let websocket_session_actor_address = websocket_context.address();
 
         self.websocket_server_actor_address
             .send(websocket_server_actor::ConnectMessage {
                 room_name: self.room_name.to_owned(),
-                websocket_session_actor_recipient: websocket_session_actor_address.recipient(),
+                resume_token: self.resume_token.take(),
+                websocket_session_actor_recipient: websocket_session_actor_address.clone().recipient(),
+                websocket_session_kick_recipient: websocket_session_actor_address.recipient(),
             })
             .into_actor(self)
             .then(|result, websocket_session_actor, websocket_context| {
                 match result {
-                    Ok(session_id) => websocket_session_actor.session_id = session_id,
+                    Ok(connect_reply) => {
+                        websocket_session_actor.session_id = connect_reply.session_id;
+
+                        if let Some(resumed_context) = &connect_reply.resumed_context {
+                            websocket_session_actor.room_name = resumed_context.room_name.to_owned();
+                            websocket_session_actor.is_streamer = resumed_context.is_streamer;
+                            websocket_session_actor.round_trip_time = resumed_context.round_trip_time;
+                            websocket_session_actor.acked_version = resumed_context.acked_version;
+                            websocket_session_actor.broadcast_interests = resumed_context.broadcast_interests.to_owned();
+                        }
+
+                        let session_reply = SessionReply {
+                            session_id: connect_reply.session_id,
+                            resume_token: connect_reply.resume_token,
+                            resumed: connect_reply.resumed_context.is_some(),
+                        };
+
+                        match serde_json::to_string(&session_reply) {
+                            Ok(serialized_session_reply) => websocket_context.text(serialized_session_reply),
+                            Err(error) => log::error!("Failed to serialize session reply: {}", error),
+                        }
+                    }
                     _ => websocket_context.stop(),
                 }
 
@@ -42,7 +120,10 @@ impl Actor for WebsocketSessionActor {
 
     fn stopping(&mut self, _: &mut Self::Context) -> Running {
         self.websocket_server_actor_address.do_send(
-            websocket_server_actor::DisconnectMessage { websocket_session_id: self.session_id }
+            websocket_server_actor::DisconnectMessage {
+                websocket_session_id: self.session_id,
+                session_context: self.session_context(),
+            }
         );
 
         Running::Stop
@@ -50,29 +131,337 @@ impl Actor for WebsocketSessionActor {
 }
 
 /// How often heartbeat pings are sent.
-const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(5);
+pub const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(5);
 /// How long before lack of client response causes a timeout.
-const CLIENT_TIMEOUT: Duration = Duration::from_secs(10);
+pub const CLIENT_TIMEOUT: Duration = Duration::from_secs(10);
+/// Largest text message that may be reassembled from continuation frames.
+pub const MAX_CONTINUATION_SIZE: usize = 64 * 1024;
+
+/// Close code sent when the client stopped answering heartbeat pings.
+const HEARTBEAT_TIMEOUT_CLOSE_CODE: ws::CloseCode = ws::CloseCode::Other(4001);
+/// Close code sent when the client broke the websocket protocol.
+const PROTOCOL_ERROR_CLOSE_CODE: ws::CloseCode = ws::CloseCode::Protocol;
+/// Close code sent when the client sent a kind of message the server doesn't accept.
+const UNSUPPORTED_DATA_CLOSE_CODE: ws::CloseCode = ws::CloseCode::Unsupported;
+/// Close code sent when the client sent text that isn't valid UTF-8.
+const INVALID_PAYLOAD_CLOSE_CODE: ws::CloseCode = ws::CloseCode::Invalid;
+/// Close code sent when the client sent a message larger than allowed.
+const MESSAGE_TOO_BIG_CLOSE_CODE: ws::CloseCode = ws::CloseCode::Size;
+/// Close code sent when the streamer kicked the client out.
+const KICKED_CLOSE_CODE: ws::CloseCode = ws::CloseCode::Other(4003);
+/// Close code sent when the client sent more messages than `WEBSOCKET_MESSAGE_RATE_LIMIT_PER_SEC` allows.
+const RATE_LIMITED_CLOSE_CODE: ws::CloseCode = ws::CloseCode::Other(4029);
+
+/// When a session that last answered at `last_heartbeat` times out. A session that just
+/// connected gets its grace period on top of `CLIENT_TIMEOUT` before its first heartbeat is due.
+fn heartbeat_deadline(last_heartbeat: Instant, connected_at: Instant, grace_period: Duration) -> Instant {
+    last_heartbeat.max(connected_at + grace_period) + CLIENT_TIMEOUT
+}
+
+/// Round-trip time of the heartbeat ping answered by a pong received at `received_at`. Pongs that
+/// don't echo the sequence number of the last ping, e.g. late answers to earlier ones, measure nothing.
+fn round_trip_time(last_ping: Option<(u64, Instant)>, pong_message: &[u8], received_at: Instant) -> Option<Duration> {
+    last_ping
+        .filter(|(ping_sequence, _)| pong_message == ping_sequence.to_be_bytes())
+        .map(|(_, ping_sent_at)| received_at.saturating_duration_since(ping_sent_at))
+}
 
 impl WebsocketSessionActor {
+    /// State the server keeps so a reconnecting client can resume this session.
+    fn session_context(&self) -> websocket_server_actor::SessionContext {
+        websocket_server_actor::SessionContext {
+            room_name: self.room_name.clone(),
+            is_streamer: self.is_streamer,
+            round_trip_time: self.round_trip_time,
+            acked_version: self.acked_version,
+            broadcast_interests: self.broadcast_interests.to_owned(),
+        }
+    }
+
+    /// Tell the client why it's being dropped before stopping the session.
+    fn close_and_stop(websocket_context: &mut ws::WebsocketContext<Self>, code: ws::CloseCode, description: &str) {
+        websocket_context.close(Some(ws::CloseReason {
+            code,
+            description: Some(description.to_owned()),
+        }));
+        websocket_context.stop();
+    }
+
     fn check_heartbeat(&self, context: &mut ws::WebsocketContext<Self>) {
         context.run_interval(HEARTBEAT_INTERVAL, |websocket_session_actor, websocket_context| {
-            if Instant::now().duration_since(websocket_session_actor.last_heartbeat) > CLIENT_TIMEOUT {
+            let heartbeat_deadline = heartbeat_deadline(
+                websocket_session_actor.last_heartbeat,
+                websocket_session_actor.connected_at,
+                websocket_session_actor.config.heartbeat_grace_period,
+            );
+
+            if Instant::now() > heartbeat_deadline {
                 log::debug!("Client session heartbeat failed, disconnecting!");
 
                 websocket_session_actor.websocket_server_actor_address.do_send(
                     websocket_server_actor::DisconnectMessage {
-                        websocket_session_id: websocket_session_actor.session_id
+                        websocket_session_id: websocket_session_actor.session_id,
+                        session_context: websocket_session_actor.session_context(),
                     }
                 );
 
-                return websocket_context.stop();
+                return WebsocketSessionActor::close_and_stop(websocket_context, HEARTBEAT_TIMEOUT_CLOSE_CODE, "heartbeat timeout");
             }
 
+            // Pings carry a sequence number so their pongs can be matched to measure round-trip time.
+            let ping_sequence = websocket_session_actor.last_ping.map_or(0, |(ping_sequence, _)| ping_sequence.wrapping_add(1));
+            websocket_session_actor.last_ping = Some((ping_sequence, Instant::now()));
+
             log::debug!("Sent ping message to client with session id {}.", websocket_session_actor.session_id);
-            websocket_context.ping(b"");
+            websocket_context.ping(&ping_sequence.to_be_bytes());
         });
     }
+
+    /// Handle a text message, whether it arrived in a single frame or was reassembled
+    /// from continuation frames.
+    fn handle_text_message(&mut self, text_message: &str, websocket_context: &mut ws::WebsocketContext<Self>) {
+        if self.message_rate_limiter.acquire().is_err() {
+            log::debug!("Client session with session id {} sent too many messages, disconnecting!", self.session_id);
+            return WebsocketSessionActor::close_and_stop(websocket_context, RATE_LIMITED_CLOSE_CODE, "rate limit exceeded");
+        }
+
+        let trimmed_message = text_message.trim();
+
+        if trimmed_message.starts_with('/') {
+            let words: Vec<&str> = trimmed_message
+                .splitn(2, ' ')
+                .collect();
+
+            match words[0] {
+                "/list" => {
+                    log::debug!("Received /list message");
+
+                    self.websocket_server_actor_address
+                        .send(websocket_server_actor::ListRoomsMessage)
+                        .into_actor(self)
+                        .then(|result, _, websocket_context| {
+                            match result {
+                                Ok(rooms) => {
+                                    for room in rooms {
+                                        websocket_context.text(room);
+                                    }
+                                }
+                                _ => log::error!("Websocket server actor failed to respond to /list command."),
+                            }
+                            fut::ready(())
+                        })
+                        .wait(websocket_context)
+                }
+                "/history" => {
+                    log::debug!("Received /history message");
+
+                    self.websocket_server_actor_address
+                        .send(websocket_server_actor::HistoryMessage {
+                            room_name: self.room_name.clone(),
+                            is_streamer: self.is_streamer,
+                        })
+                        .into_actor(self)
+                        .then(|result, websocket_session_actor, websocket_context| {
+                            match result {
+                                Ok(history) => {
+                                    let display_utc_offset = websocket_session_actor.config.display_utc_offset;
+                                    let history: Vec<_> = history
+                                        .into_iter()
+                                        .map(|song_request| song_request.to_offset(display_utc_offset))
+                                        .collect();
+
+                                    match serde_json::to_string(&history) {
+                                        Ok(serialized_history) => websocket_context.text(serialized_history),
+                                        Err(error) => log::error!("Failed to serialize history: {}", error),
+                                    }
+                                }
+                                _ => log::error!("Websocket server actor failed to respond to /history command."),
+                            }
+                            fut::ready(())
+                        })
+                        .wait(websocket_context)
+                }
+                "/stats" => {
+                    log::debug!("Received /stats message");
+
+                    self.websocket_server_actor_address
+                        .send(websocket_server_actor::RoomStatsMessage {
+                            room_name: self.room_name.clone(),
+                        })
+                        .into_actor(self)
+                        .then(|result, _, websocket_context| {
+                            match result {
+                                Ok(viewers) => match serde_json::to_string(&StatsReply { viewers }) {
+                                    Ok(serialized_stats_reply) => websocket_context.text(serialized_stats_reply),
+                                    Err(error) => log::error!("Failed to serialize /stats reply: {}", error),
+                                },
+                                _ => log::error!("Websocket server actor failed to respond to /stats command."),
+                            }
+                            fut::ready(())
+                        })
+                        .wait(websocket_context)
+                }
+                "/whoami" => {
+                    log::debug!("Received /whoami message");
+
+                    let whoami_reply = WhoamiReply {
+                        session_id: self.session_id,
+                        room_name: self.room_name.clone(),
+                        uptime_secs: self.connected_at.elapsed().as_secs(),
+                        round_trip_time_ms: self.round_trip_time.map(|round_trip_time| round_trip_time.as_secs_f64() * 1000.0),
+                    };
+
+                    match serde_json::to_string(&whoami_reply) {
+                        Ok(serialized_whoami_reply) => websocket_context.text(serialized_whoami_reply),
+                        Err(error) => log::error!("Failed to serialize /whoami reply: {}", error),
+                    }
+                }
+                "/auth" => {
+                    log::debug!("Received /auth message");
+
+                    let streamer_token = self.config.streamer_tokens_by_user_id.get(&self.room_name);
+
+                    match (words.get(1), streamer_token) {
+                        (Some(token), Some(streamer_token)) if token.trim() == streamer_token => {
+                            self.is_streamer = true;
+                            websocket_context.text("authenticated");
+
+                            self.websocket_server_actor_address.do_send(
+                                websocket_server_actor::StreamerAuthenticatedMessage {
+                                    session_id: self.session_id,
+                                    room_name: self.room_name.clone(),
+                                },
+                            );
+                        }
+                        (Some(_), _) => websocket_context.text("!!! invalid token"),
+                        (None, _) => websocket_context.text("!!! token is required"),
+                    }
+                }
+                "/kick" => {
+                    log::debug!("Received /kick message");
+
+                    if !self.is_streamer {
+                        return websocket_context.text("!!! only the room's streamer can kick sessions");
+                    }
+
+                    let session_id = match words.get(1).and_then(|session_id| session_id.trim().parse::<usize>().ok()) {
+                        Some(session_id) => session_id,
+                        None => return websocket_context.text("!!! session id is required"),
+                    };
+
+                    self.websocket_server_actor_address
+                        .send(websocket_server_actor::KickMessage {
+                            room_name: self.room_name.clone(),
+                            session_id,
+                        })
+                        .into_actor(self)
+                        .then(move |result, _, websocket_context| {
+                            match result {
+                                Ok(true) => websocket_context.text(format!("kicked {}", session_id)),
+                                Ok(false) => websocket_context.text(format!("!!! session {} isn't in this room", session_id)),
+                                _ => log::error!("Websocket server actor failed to respond to /kick command."),
+                            }
+                            fut::ready(())
+                        })
+                        .wait(websocket_context)
+                }
+                "/ack" => {
+                    log::debug!("Received /ack message");
+
+                    let version = match words.get(1).and_then(|version| version.trim().parse::<u64>().ok()) {
+                        Some(version) => version,
+                        None => return websocket_context.text("!!! version is required"),
+                    };
+
+                    self.acked_version = Some(version);
+
+                    self.websocket_server_actor_address.do_send(websocket_server_actor::AckVersionMessage {
+                        session_id: self.session_id,
+                        version,
+                    });
+
+                    websocket_context.text(format!("acked {}", version));
+                }
+                "/subscribe" => {
+                    log::debug!("Received /subscribe message");
+
+                    // Fields can be separated by commas, spaces or both, e.g. `/subscribe current, stats`.
+                    let names: Vec<&str> = words
+                        .get(1)
+                        .map_or_else(Vec::new, |names| {
+                            names
+                                .split(|character: char| character == ',' || character.is_whitespace())
+                                .filter(|name| !name.is_empty())
+                                .collect()
+                        });
+
+                    if names.is_empty() {
+                        return websocket_context.text("!!! fields are required");
+                    }
+
+                    let broadcast_interests = if names == ["all"] {
+                        None
+                    } else {
+                        let mut broadcast_interests = HashSet::new();
+
+                        for name in &names {
+                            match websocket_server_actor::BroadcastInterest::from_name(name) {
+                                Some(broadcast_interest) => broadcast_interests.insert(broadcast_interest),
+                                None => return websocket_context.text(format!("!!! unknown field: {:?}", name)),
+                            };
+                        }
+
+                        Some(broadcast_interests)
+                    };
+
+                    self.broadcast_interests = broadcast_interests.to_owned();
+
+                    self.websocket_server_actor_address.do_send(websocket_server_actor::SubscribeMessage {
+                        session_id: self.session_id,
+                        broadcast_interests,
+                    });
+
+                    websocket_context.text(format!("subscribed {}", names.join(",")));
+                }
+                "/join" => {
+                    log::debug!("Received /join message");
+
+                    if words.len() == 2 {
+                        let room_name = words[1].trim();
+
+                        if !crate::is_valid_user_id(room_name) {
+                            return websocket_context.text(format!(
+                                "!!! room name must be 1 to {} letters, digits, '-' or '_'",
+                                crate::MAX_USER_ID_LENGTH
+                            ));
+                        }
+
+                        self.room_name = room_name.to_owned();
+                        // Streamer rights only hold for the room the session authenticated in.
+                        self.is_streamer = false;
+                        // Versions count up per room, so an acknowledged one means nothing in another.
+                        self.acked_version = None;
+
+                        self.websocket_server_actor_address.do_send(websocket_server_actor::JoinMessage {
+                            session_id: self.session_id,
+                            room_name: self.room_name.clone(),
+                        });
+
+                        websocket_context.text("joined");
+                    } else {
+                        websocket_context.text("!!! room name is required");
+                    }
+                }
+                _ => websocket_context.text(format!("!!! unknown command: {:?}", trimmed_message)),
+            }
+        } else {
+            self.websocket_server_actor_address.do_send(websocket_server_actor::ClientMessage {
+                session_id: self.session_id,
+                message: trimmed_message.to_owned(),
+                room_name: self.room_name.clone(),
+            })
+        }
+    }
 }
 
 impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for WebsocketSessionActor {
@@ -83,8 +472,9 @@ impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for WebsocketSessionA
     ) {
         let websocket_message = match websocket_message {
             Ok(websocket_message) => websocket_message,
-            Err(_) => {
-                return websocket_context.stop();
+            Err(error) => {
+                log::debug!("Received invalid websocket message from client with session id {}: {}", self.session_id, error);
+                return WebsocketSessionActor::close_and_stop(websocket_context, PROTOCOL_ERROR_CLOSE_CODE, "protocol error");
             }
         };
 
@@ -94,65 +484,23 @@ impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for WebsocketSessionA
                 self.last_heartbeat = Instant::now();
                 websocket_context.pong(&ping_message);
             }
-            ws::Message::Pong(_) => {
+            ws::Message::Pong(pong_message) => {
                 log::debug!("Received pong message from client with session id {}.", self.session_id);
                 self.last_heartbeat = Instant::now();
-            }
-            ws::Message::Text(text_message) => {
-                log::debug!("Received text message from client with session id {}: {}", self.session_id, text_message);
-                let trimmed_message = text_message.trim();
-
-                if trimmed_message.starts_with('/') {
-                    let words: Vec<&str> = trimmed_message
-                        .splitn(2, ' ')
-                        .collect();
-
-                    match words[0] {
-                        "/list" => {
-                            log::debug!("Received /list message");
-
-                            self.websocket_server_actor_address
-                                .send(websocket_server_actor::ListRoomsMessage)
-                                .into_actor(self)
-                                .then(|result, _, websocket_context| {
-                                    match result {
-                                        Ok(rooms) => {
-                                            for room in rooms {
-                                                websocket_context.text(room);
-                                            }
-                                        }
-                                        _ => log::error!("Websocket server actor failed to respond to /list command."),
-                                    }
-                                    fut::ready(())
-                                })
-                                .wait(websocket_context)
-                        }
-                        "/join" => {
-                            log::debug!("Received /join message");
-
-                            if words.len() == 2 {
-                                self.room_name = words[1].to_owned();
 
-                                self.websocket_server_actor_address.do_send(websocket_server_actor::JoinMessage {
-                                    session_id: self.session_id,
-                                    room_name: self.room_name.clone(),
-                                });
+                if let Some(round_trip_time) = round_trip_time(self.last_ping, &pong_message, self.last_heartbeat) {
+                    self.round_trip_time = Some(round_trip_time);
 
-                                websocket_context.text("joined");
-                            } else {
-                                websocket_context.text("!!! room name is required");
-                            }
-                        }
-                        _ => websocket_context.text(format!("!!! unknown command: {:?}", trimmed_message)),
-                    }
-                } else {
-                    self.websocket_server_actor_address.do_send(websocket_server_actor::ClientMessage {
+                    self.websocket_server_actor_address.do_send(websocket_server_actor::RoundTripTimeMessage {
                         session_id: self.session_id,
-                        message: trimmed_message.to_owned(),
-                        room_name: self.room_name.clone(),
-                    })
+                        round_trip_time,
+                    });
                 }
             }
+            ws::Message::Text(text_message) => {
+                log::debug!("Received text message from client with session id {}: {}", self.session_id, text_message);
+                self.handle_text_message(&text_message, websocket_context);
+            }
             ws::Message::Binary(_) => log::error!("Unexpected binary websocket message."),
             ws::Message::Close(close_reason) => {
                 if let Some(close_reason) = &close_reason {
@@ -163,15 +511,89 @@ impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for WebsocketSessionA
                 websocket_context.close(close_reason);
                 websocket_context.stop();
             }
-            ws::Message::Continuation(_) => {
-                log::debug!("Received continuation message from client with session id {}.", self.session_id);
-                websocket_context.stop();
+            ws::Message::Continuation(item) => {
+                let (fragment, is_last_fragment) = match item {
+                    Item::FirstText(fragment) => {
+                        if self.continuation_buffer.is_some() {
+                            log::debug!("Received interleaved continuation message from client with session id {}.", self.session_id);
+                            return WebsocketSessionActor::close_and_stop(websocket_context, PROTOCOL_ERROR_CLOSE_CODE, "interleaved continuation");
+                        }
+                        self.continuation_buffer = Some(Vec::new());
+                        (fragment, false)
+                    }
+                    Item::FirstBinary(_) => {
+                        log::debug!("Received binary continuation message from client with session id {}.", self.session_id);
+                        return WebsocketSessionActor::close_and_stop(websocket_context, UNSUPPORTED_DATA_CLOSE_CODE, "binary messages are not supported");
+                    }
+                    Item::Continue(fragment) => (fragment, false),
+                    Item::Last(fragment) => (fragment, true),
+                };
+
+                let continuation_buffer = match self.continuation_buffer.as_mut() {
+                    Some(continuation_buffer) => continuation_buffer,
+                    None => {
+                        log::debug!("Received continuation message without a first fragment from client with session id {}.", self.session_id);
+                        return WebsocketSessionActor::close_and_stop(websocket_context, PROTOCOL_ERROR_CLOSE_CODE, "continuation without a first fragment");
+                    }
+                };
+
+                if continuation_buffer.len() + fragment.len() > MAX_CONTINUATION_SIZE {
+                    log::debug!("Continuation message from client with session id {} is too large, disconnecting!", self.session_id);
+                    return WebsocketSessionActor::close_and_stop(websocket_context, MESSAGE_TOO_BIG_CLOSE_CODE, "message too large");
+                }
+
+                continuation_buffer.extend_from_slice(&fragment);
+
+                if is_last_fragment {
+                    let continuation_buffer = self.continuation_buffer.take().unwrap_or_default();
+
+                    match String::from_utf8(continuation_buffer) {
+                        Ok(text_message) => {
+                            log::debug!("Received continuation text message from client with session id {}: {}", self.session_id, text_message);
+                            self.handle_text_message(&text_message, websocket_context);
+                        }
+                        Err(_) => {
+                            log::debug!("Received invalid UTF-8 continuation message from client with session id {}.", self.session_id);
+                            WebsocketSessionActor::close_and_stop(websocket_context, INVALID_PAYLOAD_CLOSE_CODE, "invalid UTF-8");
+                        }
+                    }
+                }
             }
             ws::Message::Nop => (),
         }
     }
 }
 
+/// Sent once connected, with the token to reconnect with to resume this session.
+#[derive(Serialize)]
+#[serde(tag = "type", rename = "session", rename_all = "camelCase")]
+struct SessionReply {
+    session_id: usize,
+    resume_token: String,
+    /// Whether an earlier session was resumed.
+    resumed: bool,
+}
+
+/// Reply to the `/whoami` command.
+#[derive(Serialize)]
+#[serde(tag = "type", rename = "whoami", rename_all = "camelCase")]
+struct WhoamiReply {
+    session_id: usize,
+    room_name: String,
+    /// How long the session has been connected, in whole seconds.
+    uptime_secs: u64,
+    /// Latest heartbeat round-trip time, in milliseconds, once a ping has been answered.
+    round_trip_time_ms: Option<f64>,
+}
+
+/// Reply to the `/stats` command.
+#[derive(Serialize)]
+#[serde(tag = "type", rename = "stats")]
+struct StatsReply {
+    /// Websocket sessions currently in the room, including the asking one.
+    viewers: usize,
+}
+
 #[derive(Message)]
 #[rtype(result = "()")]
 pub struct WebsocketReplyMessage {
@@ -185,3 +607,57 @@ impl Handler<WebsocketReplyMessage> for WebsocketSessionActor {
         websocket_context.text(websocket_reply_message.message);
     }
 }
+
+/// Tells a session it was kicked, so it closes its connection.
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct KickSessionMessage;
+
+impl Handler<KickSessionMessage> for WebsocketSessionActor {
+    type Result = ();
+
+    fn handle(&mut self, _: KickSessionMessage, websocket_context: &mut Self::Context) {
+        log::debug!("Client with session id {} was kicked, disconnecting!", self.session_id);
+        WebsocketSessionActor::close_and_stop(websocket_context, KICKED_CLOSE_CODE, "kicked by the streamer");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn heartbeat_deadline_adds_the_grace_period_to_the_client_timeout() {
+        let connected_at = Instant::now();
+        let grace_period = Duration::from_secs(30);
+
+        assert_eq!(heartbeat_deadline(connected_at, connected_at, grace_period), connected_at + grace_period + CLIENT_TIMEOUT);
+        assert_eq!(heartbeat_deadline(connected_at, connected_at, Duration::from_secs(0)), connected_at + CLIENT_TIMEOUT);
+    }
+
+    #[test]
+    fn heartbeat_deadline_follows_heartbeats_after_the_grace_period() {
+        let connected_at = Instant::now();
+        let last_heartbeat = connected_at + Duration::from_secs(60);
+
+        assert_eq!(heartbeat_deadline(last_heartbeat, connected_at, Duration::from_secs(30)), last_heartbeat + CLIENT_TIMEOUT);
+    }
+
+    #[test]
+    fn round_trip_time_measures_pongs_echoing_the_last_ping() {
+        let ping_sent_at = Instant::now();
+        let received_at = ping_sent_at + Duration::from_millis(42);
+
+        assert_eq!(round_trip_time(Some((7, ping_sent_at)), &7u64.to_be_bytes(), received_at), Some(Duration::from_millis(42)));
+    }
+
+    #[test]
+    fn round_trip_time_ignores_pongs_for_other_pings() {
+        let ping_sent_at = Instant::now();
+        let received_at = ping_sent_at + Duration::from_millis(42);
+
+        assert_eq!(round_trip_time(Some((7, ping_sent_at)), &6u64.to_be_bytes(), received_at), None);
+        assert_eq!(round_trip_time(Some((7, ping_sent_at)), b"", received_at), None);
+        assert_eq!(round_trip_time(None, &7u64.to_be_bytes(), received_at), None);
+    }
+}